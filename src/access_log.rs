@@ -0,0 +1,435 @@
+use crate::http_utils::{RequestId, UpstreamStats};
+use crate::routes::RouteKey;
+use crate::stability_patterns::{CircuitState, StabilityPatterns};
+use crate::telemetry::Trace;
+use crate::tls_utils::PeerIdentity;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use chrono::{DateTime, Local, SecondsFormat};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use serde_json::json;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Access log: one line per request on stdout, either a JSON object with the configured
+// fields (in order) or a text line rendered from a template with {field} placeholders.
+// Missing values are null in JSON and - in text. Routes can be sampled (route=ratio),
+// server errors are always logged.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessLogFormat {
+    Json,
+    Text,
+    Off,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessLogField {
+    Timestamp,
+    RequestId,
+    ClientIp,
+    Identity,
+    Method,
+    Path,
+    Query,
+    Route,
+    Status,
+    BytesIn,
+    BytesOut,
+    UpstreamLatency,
+    Latency,
+    Retries,
+    Circuit,
+    UserAgent,
+    TraceId,
+}
+
+// ratio of the requests of a route that are logged
+#[derive(Clone, Debug)]
+pub struct SampleRule {
+    pub route: String,
+    pub ratio: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessLogSettings {
+    pub format: AccessLogFormat,
+    pub fields: Vec<AccessLogField>,
+    pub template: String,
+    pub samples: Vec<SampleRule>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Field(AccessLogField),
+}
+
+pub struct AccessLogger {
+    settings: AccessLogSettings,
+    template: Vec<Segment>,
+    sp: Arc<StabilityPatterns>,
+}
+
+// request details captured before the request is handled
+struct RequestInfo {
+    started: DateTime<Local>,
+    method: String,
+    path: String,
+    query: String,
+    client_ip: Option<String>,
+    user_agent: Option<String>,
+    bytes_in: Option<u64>,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(AccessLogFormat::Json),
+            "text" => Ok(AccessLogFormat::Text),
+            "off" => Ok(AccessLogFormat::Off),
+            _ => Err(format!("unknown access log format: {}", s)),
+        }
+    }
+}
+
+impl FromStr for AccessLogField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "timestamp" => Ok(AccessLogField::Timestamp),
+            "request_id" => Ok(AccessLogField::RequestId),
+            "client_ip" => Ok(AccessLogField::ClientIp),
+            "identity" => Ok(AccessLogField::Identity),
+            "method" => Ok(AccessLogField::Method),
+            "path" => Ok(AccessLogField::Path),
+            "query" => Ok(AccessLogField::Query),
+            "route" => Ok(AccessLogField::Route),
+            "status" => Ok(AccessLogField::Status),
+            "bytes_in" => Ok(AccessLogField::BytesIn),
+            "bytes_out" => Ok(AccessLogField::BytesOut),
+            "upstream_latency_ms" => Ok(AccessLogField::UpstreamLatency),
+            "latency_ms" => Ok(AccessLogField::Latency),
+            "retries" => Ok(AccessLogField::Retries),
+            "circuit" => Ok(AccessLogField::Circuit),
+            "user_agent" => Ok(AccessLogField::UserAgent),
+            "trace_id" => Ok(AccessLogField::TraceId),
+            _ => Err(format!("unknown access log field: {}", s)),
+        }
+    }
+}
+
+impl AccessLogField {
+    fn name(&self) -> &'static str {
+        match self {
+            AccessLogField::Timestamp => "timestamp",
+            AccessLogField::RequestId => "request_id",
+            AccessLogField::ClientIp => "client_ip",
+            AccessLogField::Identity => "identity",
+            AccessLogField::Method => "method",
+            AccessLogField::Path => "path",
+            AccessLogField::Query => "query",
+            AccessLogField::Route => "route",
+            AccessLogField::Status => "status",
+            AccessLogField::BytesIn => "bytes_in",
+            AccessLogField::BytesOut => "bytes_out",
+            AccessLogField::UpstreamLatency => "upstream_latency_ms",
+            AccessLogField::Latency => "latency_ms",
+            AccessLogField::Retries => "retries",
+            AccessLogField::Circuit => "circuit",
+            AccessLogField::UserAgent => "user_agent",
+            AccessLogField::TraceId => "trace_id",
+        }
+    }
+}
+
+impl FromStr for SampleRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s
+            .rfind('=')
+            .ok_or_else(|| format!("access log sample {}: expected route=ratio", s))?;
+        let ratio: f64 = s[i + 1..]
+            .parse()
+            .map_err(|_| format!("access log sample {}: invalid ratio", s))?;
+        if !(0.0..=1.0).contains(&ratio) {
+            return Err(format!("access log sample {}: ratio must be in [0, 1]", s));
+        }
+        Ok(SampleRule {
+            route: s[..i].to_string(),
+            ratio,
+        })
+    }
+}
+
+fn milliseconds(d: chrono::Duration) -> serde_json::Value {
+    json!(d.num_microseconds().unwrap_or(0) as f64 / 1000.0)
+}
+
+fn parse_template(template: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("access log template: unclosed {{ in {}", template))?;
+        if start > 0 {
+            segments.push(Segment::Literal(rest[..start].to_string()));
+        }
+        segments.push(Segment::Field(rest[start + 1..start + end].parse()?));
+        rest = &rest[start + end + 1..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest.to_string()));
+    }
+    Ok(segments)
+}
+
+// whether a request is logged, roll is uniform in [0, 1)
+fn sampled(samples: &[SampleRule], route: Option<&str>, status: u16, roll: f64) -> bool {
+    if status >= 500 {
+        return true;
+    }
+    let rule = route.and_then(|route| samples.iter().find(|r| r.route == route));
+    match rule {
+        Some(rule) => roll < rule.ratio,
+        None => true,
+    }
+}
+
+impl AccessLogger {
+    pub fn new(settings: AccessLogSettings, sp: Arc<StabilityPatterns>) -> Result<Self, String> {
+        let template = parse_template(&settings.template)?;
+        Ok(Self {
+            settings,
+            template,
+            sp,
+        })
+    }
+
+    fn sampled(&self, route: Option<&str>, status: u16) -> bool {
+        sampled(&self.settings.samples, route, status, rand::random())
+    }
+
+    fn log<B: MessageBody>(&self, info: RequestInfo, res: &ServiceResponse<B>) {
+        let status = res.status().as_u16();
+        let extensions = res.request().extensions();
+        let route = extensions.get::<RouteKey>().map(|r| r.0.clone());
+        if !self.sampled(route.as_deref(), status) {
+            return;
+        }
+        let upstream = extensions.get::<UpstreamStats>();
+        let value = |field: &AccessLogField| -> serde_json::Value {
+            match field {
+                AccessLogField::Timestamp => {
+                    json!(info.started.to_rfc3339_opts(SecondsFormat::Millis, true))
+                }
+                AccessLogField::RequestId => json!(extensions.get::<RequestId>().map(|id| &id.0)),
+                AccessLogField::ClientIp => json!(info.client_ip),
+                AccessLogField::Identity => json!(extensions.get::<PeerIdentity>().map(|id| &id.0)),
+                AccessLogField::Method => json!(info.method),
+                AccessLogField::Path => json!(info.path),
+                AccessLogField::Query => json!(info.query),
+                AccessLogField::Route => json!(route),
+                AccessLogField::Status => json!(status),
+                AccessLogField::BytesIn => json!(info.bytes_in),
+                AccessLogField::BytesOut => match res.response().body().size() {
+                    BodySize::Sized(n) => json!(n),
+                    BodySize::Empty | BodySize::None => json!(0),
+                    BodySize::Stream => serde_json::Value::Null,
+                },
+                AccessLogField::UpstreamLatency => match upstream {
+                    Some(u) => milliseconds(u.latency),
+                    None => serde_json::Value::Null,
+                },
+                AccessLogField::Latency => milliseconds(Local::now() - info.started),
+                AccessLogField::Retries => json!(upstream.map(|u| u.attempts - 1).unwrap_or(0)),
+                AccessLogField::Circuit => {
+                    match route.as_ref().and_then(|r| self.sp.circuit_state(r)) {
+                        Some(CircuitState::Closed) => json!("closed"),
+                        Some(CircuitState::Open) => json!("open"),
+                        Some(CircuitState::HalfOpen) => json!("half_open"),
+                        None => serde_json::Value::Null,
+                    }
+                }
+                AccessLogField::UserAgent => json!(info.user_agent),
+                AccessLogField::TraceId => {
+                    json!(extensions.get::<Trace>().map(|t| t.context.trace_id_hex()))
+                }
+            }
+        };
+
+        let line = match self.settings.format {
+            AccessLogFormat::Json => {
+                let fields: Vec<String> = self
+                    .settings
+                    .fields
+                    .iter()
+                    .map(|f| format!("{}:{}", json!(f.name()), value(f)))
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            }
+            AccessLogFormat::Text => self
+                .template
+                .iter()
+                .map(|segment| match segment {
+                    Segment::Literal(l) => l.clone(),
+                    Segment::Field(f) => match value(f) {
+                        serde_json::Value::Null => "-".to_string(),
+                        serde_json::Value::String(s) => s,
+                        v => v.to_string(),
+                    },
+                })
+                .collect(),
+            AccessLogFormat::Off => return,
+        };
+        let _ = writeln!(std::io::stdout().lock(), "{}", line);
+    }
+}
+
+// Middleware writing the access log line once the response is ready
+pub struct AccessLog {
+    logger: Arc<AccessLogger>,
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S,
+    logger: Arc<AccessLogger>,
+}
+
+impl AccessLog {
+    pub fn new(logger: Arc<AccessLogger>) -> Self {
+        Self { logger }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AccessLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AccessLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AccessLogMiddleware {
+            service,
+            logger: self.logger.clone(),
+        })
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for AccessLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if self.logger.settings.format == AccessLogFormat::Off {
+            return Box::pin(self.service.call(req));
+        }
+        let info = RequestInfo {
+            started: Local::now(),
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            query: req.query_string().to_string(),
+            client_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+            user_agent: req
+                .headers()
+                .get("user-agent")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            bytes_in: req
+                .headers()
+                .get("content-length")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok()),
+        };
+        let logger = self.logger.clone();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            logger.log(info, &res);
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates() {
+        let segments =
+            parse_template("{client_ip} - [{timestamp}] \"{method} {path}\" {status}").unwrap();
+        assert_eq!(
+            segments,
+            [
+                Segment::Field(AccessLogField::ClientIp),
+                Segment::Literal(" - [".to_string()),
+                Segment::Field(AccessLogField::Timestamp),
+                Segment::Literal("] \"".to_string()),
+                Segment::Field(AccessLogField::Method),
+                Segment::Literal(" ".to_string()),
+                Segment::Field(AccessLogField::Path),
+                Segment::Literal("\" ".to_string()),
+                Segment::Field(AccessLogField::Status),
+            ]
+        );
+        assert_eq!(
+            parse_template("static text").unwrap(),
+            [Segment::Literal("static text".to_string())]
+        );
+        assert!(parse_template("").unwrap().is_empty());
+        assert!(parse_template("{status} {nope}").is_err());
+        assert!(parse_template("{status").is_err());
+        assert!(parse_template("{}").is_err());
+    }
+
+    #[test]
+    fn sample_rules() {
+        let rule: SampleRule = "/health=0.01".parse().unwrap();
+        assert_eq!((rule.route.as_str(), rule.ratio), ("/health", 0.01));
+        // the last = separates the ratio, routes may contain one
+        let rule: SampleRule = "api_$1=~^/api/(v[0-9]+)/=0.5".parse().unwrap();
+        assert_eq!(rule.route, "api_$1=~^/api/(v[0-9]+)/");
+        assert_eq!(rule.ratio, 0.5);
+        assert!("/health".parse::<SampleRule>().is_err());
+        assert!("/health=often".parse::<SampleRule>().is_err());
+        assert!("/health=1.5".parse::<SampleRule>().is_err());
+        assert!("/health=-0.1".parse::<SampleRule>().is_err());
+    }
+
+    #[test]
+    fn sampling() {
+        let samples: Vec<SampleRule> = ["/health=0", "/users/{id}=0.25"]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect();
+        assert!(!sampled(&samples, Some("/health"), 200, 0.0));
+        assert!(sampled(&samples, Some("/users/{id}"), 200, 0.2));
+        assert!(!sampled(&samples, Some("/users/{id}"), 404, 0.3));
+        // unsampled routes and server errors are always logged
+        assert!(sampled(&samples, Some("/orders"), 200, 0.99));
+        assert!(sampled(&samples, None, 200, 0.99));
+        assert!(sampled(&samples, Some("/health"), 503, 0.99));
+    }
+}
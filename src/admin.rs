@@ -0,0 +1,291 @@
+use crate::health_check::Readiness;
+use crate::metrics::Metrics;
+use crate::process_manager::ProcessState;
+use crate::rolling_restart::{RestartError, Restarter};
+use crate::shutdown::Shutdown;
+use crate::tls_utils::CertificateStatus;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Local};
+use log::info;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Orchestrator probes served by gasket itself (never proxied to the child):
+// livez: gasket is up and serving
+// readyz: the child passed its health checks, the certificates are valid and gasket is not
+// shutting down
+// healthz: readyz plus the supervised processes are running (or completed)
+// All answer 200 or 503 with a JSON status, ?verbose adds the details behind it.
+// The Prometheus metrics are served next to the probes.
+// With an admin token, POST on the restart path with Authorization: Bearer <token> makes a
+// rolling restart of the proxied process: 200 once requests go to the new process, 409 while
+// another one runs, 503 when the new process never got healthy, 401 without the token.
+
+#[derive(Clone, Debug)]
+pub struct HealthPaths {
+    pub healthz: String,
+    pub readyz: String,
+    pub livez: String,
+    pub metrics: String,
+    pub restart: String,
+}
+
+// rolling restarts on the admin endpoint, for the holders of the token
+pub struct AdminRestart {
+    pub token: String,
+    pub restarter: Restarter,
+}
+
+pub struct HealthState {
+    pub readiness: Arc<Readiness>,
+    pub processes: Vec<Arc<Mutex<ProcessState>>>,
+    shutdown: Arc<Shutdown>,
+    certificates: Mutex<Vec<CertificateStatus>>,
+    // TLS reload generation the certificate statuses were read at
+    certificates_generation: AtomicU64,
+    restart: Option<AdminRestart>,
+    started_at: DateTime<Local>,
+    paths: HealthPaths,
+}
+
+impl HealthState {
+    pub fn new(
+        readiness: Arc<Readiness>,
+        processes: Vec<Arc<Mutex<ProcessState>>>,
+        shutdown: Arc<Shutdown>,
+        paths: HealthPaths,
+        restart: Option<AdminRestart>,
+    ) -> Self {
+        Self {
+            readiness,
+            processes,
+            shutdown,
+            certificates: Mutex::new(Vec::new()),
+            certificates_generation: AtomicU64::new(crate::tls_utils::generation()),
+            restart,
+            started_at: Local::now(),
+            paths,
+        }
+    }
+
+    // certificates served by gasket, checked for validity on every probe
+    pub fn watch_certificate(&self, path: String) {
+        match crate::tls_utils::CertificateManager::certificate_status(path.clone()) {
+            Ok(status) => {
+                info!("Certificate {} valid until {}", path, status.not_after);
+                self.certificates.lock().unwrap().push(status);
+            }
+            Err(e) => info!("Certificate {} can't be checked: {}", path, e),
+        }
+    }
+
+    pub fn started_at(&self) -> DateTime<Local> {
+        self.started_at
+    }
+
+    // reads the certificates again once TLS was reloaded, a certificate that can't be read
+    // keeps its previous status
+    fn refresh_certificates(&self) {
+        let generation = crate::tls_utils::generation();
+        if self
+            .certificates_generation
+            .swap(generation, Ordering::SeqCst)
+            == generation
+        {
+            return;
+        }
+        for status in self.certificates.lock().unwrap().iter_mut() {
+            match crate::tls_utils::CertificateManager::certificate_status(status.path.clone()) {
+                Ok(reloaded) => {
+                    info!(
+                        "Certificate {} valid until {}",
+                        reloaded.path, reloaded.not_after
+                    );
+                    *status = reloaded;
+                }
+                Err(e) => info!("Certificate {} can't be checked: {}", status.path, e),
+            }
+        }
+    }
+
+    fn certificates_valid(&self) -> bool {
+        self.refresh_certificates();
+        self.certificates.lock().unwrap().iter().all(|c| c.valid())
+    }
+
+    fn live(&self) -> bool {
+        true
+    }
+
+    fn ready(&self) -> bool {
+        !self.shutdown.is_requested() && self.readiness.is_ready() && self.certificates_valid()
+    }
+
+    fn healthy(&self) -> bool {
+        self.ready()
+            && self.processes.iter().all(|p| {
+                let process = p.lock().unwrap();
+                !process.managed || process.running || process.completed
+            })
+    }
+
+    fn details(&self) -> serde_json::Value {
+        self.refresh_certificates();
+        let certificates: Vec<serde_json::Value> = self
+            .certificates
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| {
+                json!({
+                    "path": c.path,
+                    "subject": c.subject,
+                    "not_before": c.not_before,
+                    "not_after": c.not_after,
+                    "expires_in_seconds": (c.not_after - Local::now()).num_seconds(),
+                    "valid": c.valid(),
+                })
+            })
+            .collect();
+        let processes: Vec<ProcessState> = self
+            .processes
+            .iter()
+            .map(|p| p.lock().unwrap().clone())
+            .collect();
+        json!({
+            "gasket": {
+                "pid": std::process::id(),
+                "started_at": self.started_at,
+                "uptime_seconds": (Local::now() - self.started_at).num_seconds(),
+                "shutting_down": self.shutdown.is_requested(),
+            },
+            "process": processes.iter().find(|p| p.proxied),
+            "processes": processes,
+            "upstream": {
+                "ready": self.readiness.is_ready(),
+            },
+            "certificates": certificates,
+        })
+    }
+}
+
+// registers the probes and metrics on an app, either the proxy itself or the admin server
+pub fn endpoints(cfg: &mut web::ServiceConfig, health: &Arc<HealthState>, metrics: &Arc<Metrics>) {
+    cfg.app_data(web::Data::new(health.clone()))
+        .app_data(web::Data::new(metrics.clone()))
+        .route(&health.paths.livez, web::get().to(livez))
+        .route(&health.paths.readyz, web::get().to(readyz))
+        .route(&health.paths.healthz, web::get().to(healthz))
+        .route(
+            &health.paths.metrics,
+            web::get().to(crate::metrics::metrics),
+        );
+    if health.restart.is_some() {
+        cfg.route(&health.paths.restart, web::post().to(restart));
+    }
+}
+
+fn verbose(query: &HashMap<String, String>) -> bool {
+    match query.get("verbose") {
+        Some(v) => v != "0" && v != "false",
+        None => false,
+    }
+}
+
+fn probe_response(ok: bool, details: Option<serde_json::Value>) -> HttpResponse {
+    let status = if ok { "ok" } else { "fail" };
+    let body = match details {
+        Some(mut details) => {
+            details["status"] = json!(status);
+            details
+        }
+        None => json!({ "status": status }),
+    };
+    if ok {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+async fn livez(
+    health: web::Data<Arc<HealthState>>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let details = if verbose(&query) {
+        Some(json!({ "gasket": health.details()["gasket"] }))
+    } else {
+        None
+    };
+    probe_response(health.live(), details)
+}
+
+async fn readyz(
+    health: web::Data<Arc<HealthState>>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let details = if verbose(&query) {
+        let details = health.details();
+        Some(json!({
+            "upstream": details["upstream"],
+            "certificates": details["certificates"],
+        }))
+    } else {
+        None
+    };
+    probe_response(health.ready(), details)
+}
+
+async fn healthz(
+    health: web::Data<Arc<HealthState>>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let details = if verbose(&query) {
+        Some(health.details())
+    } else {
+        None
+    };
+    probe_response(health.healthy(), details)
+}
+
+// compares without returning early, the time taken doesn't tell how much of the token matched
+fn token_matches(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn restart(health: web::Data<Arc<HealthState>>, req: HttpRequest) -> HttpResponse {
+    let restart = match health.restart.as_ref() {
+        Some(restart) => restart,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let authorized = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| token_matches(given.as_bytes(), restart.token.as_bytes()));
+    if !authorized {
+        info!("Admin: unauthorized restart request");
+        return HttpResponse::Unauthorized()
+            .insert_header(("WWW-Authenticate", "Bearer"))
+            .json(json!({ "status": "unauthorized" }));
+    }
+    info!("Admin: rolling restart requested");
+    match restart.restarter.restart().await {
+        Ok(port) => HttpResponse::Ok().json(json!({ "status": "restarted", "port": port })),
+        Err(RestartError::InProgress) => {
+            HttpResponse::Conflict().json(json!({ "status": "in_progress" }))
+        }
+        Err(RestartError::Failed(e)) => {
+            info!("Admin: rolling restart failed: {}", e);
+            HttpResponse::ServiceUnavailable().json(json!({ "status": "failed", "error": e }))
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+// Environment of the supervised processes, built on every spawn:
+// 1. gasket's environment, without its own configuration (GASKET_* unless allowed by name),
+//    filtered by the allowlist (every variable when empty) and the denylist. Patterns are
+//    names, PREFIX_* matches a prefix.
+// 2. the --env-file dotenv files then the --env variables, then the env of the process
+//    (--processes). Their values are templates: ${NAME} is a gasket setting (GASKET_UPSTREAM_PORT,
+//    GASKET_LISTEN_PORT, GASKET_REQUEST_ID_HEADER, GASKET_SERVICE_NAME, GASKET_PROCESS_NAME) or a
+//    variable already set, unknown names are empty, $$ is a $.
+// 3. secret files: for the names listed (patterns too), FOO_FILE=/run/secrets/foo becomes FOO
+//    with the content of the file (without its trailing newline), read again on every restart.
+//    Only listed names, SSL_CERT_FILE and the like are paths that must stay paths.
+// PORT is set last for the proxied process.
+
+#[derive(Clone, Debug, Default)]
+pub struct EnvSettings {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    // from the env files then --env, in order
+    pub vars: Vec<(String, String)>,
+    // template values
+    pub settings: BTreeMap<String, String>,
+    pub secret_files: Vec<String>,
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+// KEY=VALUE, for --env
+pub fn parse_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE: {}", s)),
+    }
+}
+
+// a dotenv file: KEY=VALUE lines, optionally prefixed by export, # comments, 'literal' and
+// "escaped" (\n \t \" \\) values, unquoted values end at a # comment
+pub fn parse_dotenv(content: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected KEY=VALUE", i + 1))?;
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(format!("line {}: invalid name {:?}", i + 1, key));
+        }
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            quoted
+                .strip_suffix('\'')
+                .ok_or_else(|| format!("line {}: unterminated '", i + 1))?
+                .to_string()
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            let quoted = quoted
+                .strip_suffix('"')
+                .ok_or_else(|| format!("line {}: unterminated \"", i + 1))?;
+            let mut unescaped = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    unescaped.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('n') => unescaped.push('\n'),
+                    Some('t') => unescaped.push('\t'),
+                    Some(c) => unescaped.push(c),
+                    None => unescaped.push('\\'),
+                }
+            }
+            unescaped
+        } else {
+            match value.find(" #") {
+                Some(comment) => value[..comment].trim_end().to_string(),
+                None => value.to_string(),
+            }
+        };
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+// replaces ${NAME} with a setting or a variable
+fn expand(
+    value: &str,
+    settings: &BTreeMap<String, String>,
+    env: &BTreeMap<String, String>,
+) -> String {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = after;
+        } else if let Some((name, after)) = rest.strip_prefix("${").and_then(|r| r.split_once('}'))
+        {
+            if let Some(v) = settings.get(name).or_else(|| env.get(name)) {
+                expanded.push_str(v);
+            }
+            rest = after;
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+impl EnvSettings {
+    // reads the env files, in order
+    pub fn load_files(&mut self, paths: &[String]) -> Result<(), String> {
+        for path in paths {
+            let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            let vars = parse_dotenv(&content).map_err(|e| format!("{}: {}", path, e))?;
+            self.vars.extend(vars);
+        }
+        Ok(())
+    }
+
+    fn inherited(&self, name: &str) -> bool {
+        if name.starts_with("GASKET_") && !self.allow.iter().any(|a| a == name) {
+            return false;
+        }
+        (self.allow.is_empty() || self.allow.iter().any(|a| matches(a, name)))
+            && !self.deny.iter().any(|d| matches(d, name))
+    }
+
+    // the environment of a process
+    pub fn build(
+        &self,
+        process: &str,
+        process_env: &BTreeMap<String, String>,
+    ) -> Result<BTreeMap<String, String>, String> {
+        let mut env: BTreeMap<String, String> = std::env::vars()
+            .filter(|(name, _)| self.inherited(name))
+            .collect();
+        let mut settings = self.settings.clone();
+        settings.insert("GASKET_PROCESS_NAME".to_string(), process.to_string());
+        let vars = self.vars.iter().map(|(name, value)| (name, value));
+        for (name, value) in vars.chain(process_env.iter()) {
+            let value = expand(value, &settings, &env);
+            env.insert(name.clone(), value);
+        }
+        let files: Vec<(String, String)> = env
+            .iter()
+            .filter_map(|(name, path)| {
+                let name = name.strip_suffix("_FILE")?;
+                Some((name.to_string(), path.clone()))
+            })
+            .filter(|(name, _)| self.secret_files.iter().any(|s| matches(s, name)))
+            .collect();
+        for (name, path) in files {
+            let secret =
+                fs::read_to_string(&path).map_err(|e| format!("{}_FILE {}: {}", name, path, e))?;
+            let secret = secret
+                .strip_suffix('\n')
+                .map(|s| s.strip_suffix('\r').unwrap_or(s))
+                .unwrap_or(&secret);
+            env.remove(&format!("{}_FILE", name));
+            env.insert(name, secret.to_string());
+        }
+        Ok(env)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dotenv() {
+        let vars = parse_dotenv(
+            "# comment\n\nexport A=1\nB = two words # comment\nC='${X} #'\nD=\"a\\nb \\\"q\\\"\"\nE=\n",
+        )
+        .unwrap();
+        let expected = [
+            ("A", "1"),
+            ("B", "two words"),
+            ("C", "${X} #"),
+            ("D", "a\nb \"q\""),
+            ("E", ""),
+        ];
+        assert_eq!(vars.len(), expected.len());
+        for ((key, value), (k, v)) in vars.iter().zip(expected.iter()) {
+            assert_eq!((key.as_str(), value.as_str()), (*k, *v));
+        }
+        assert!(parse_dotenv("NOVALUE").is_err());
+        assert!(parse_dotenv("A='x").is_err());
+    }
+
+    #[test]
+    fn templates() {
+        let settings: BTreeMap<String, String> =
+            [("GASKET_UPSTREAM_PORT".to_string(), "3001".to_string())].into();
+        let env: BTreeMap<String, String> = [("HOME".to_string(), "/root".to_string())].into();
+        assert_eq!(
+            expand(
+                "http://localhost:${GASKET_UPSTREAM_PORT}${HOME}/${NONE}$$5 $x",
+                &settings,
+                &env
+            ),
+            "http://localhost:3001/root/$5 $x"
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let settings = EnvSettings {
+            allow: vec![
+                "AWS_*".to_string(),
+                "HOME".to_string(),
+                "GASKET_KEEP".to_string(),
+            ],
+            deny: vec!["AWS_SECRET_*".to_string()],
+            ..Default::default()
+        };
+        assert!(settings.inherited("AWS_REGION"));
+        assert!(settings.inherited("HOME"));
+        assert!(settings.inherited("GASKET_KEEP"));
+        assert!(!settings.inherited("AWS_SECRET_ACCESS_KEY"));
+        assert!(!settings.inherited("PATH"));
+        assert!(!EnvSettings::default().inherited("GASKET_PRIVATE_KEY"));
+    }
+}
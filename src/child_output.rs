@@ -0,0 +1,318 @@
+use chrono::{DateTime, Local, SecondsFormat};
+use regex::Regex;
+use serde_json::json;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time;
+
+// Child output capture: stdout and stderr of the child are piped through gasket and written
+// line by line to gasket's stdout and stderr, tagged with the source, pid and stream.
+// - lines matching the multiline pattern are continuation lines and are merged with the
+//   previous line (stack traces), a record is flushed when a new record starts or after
+//   MULTILINE_FLUSH_MS without output
+// - at most rate_limit records per second are written per stream, the excess is dropped and
+//   reported once output is allowed again
+// - lines are cut at MAX_LINE_BYTES and at most BACKLOG_LINES wait to be written: a child
+//   writing faster than gasket's own output drains loses lines (reported like the rate limit)
+//   instead of growing gasket's memory
+const MULTILINE_FLUSH_MS: u64 = 100;
+const MULTILINE_MAX_LINES: usize = 500;
+const MAX_LINE_BYTES: usize = 16 * 1024;
+const BACKLOG_LINES: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputSettings {
+    pub format: OutputFormat,
+    pub multiline: Option<Regex>,
+    // records per second and per stream, 0 is unlimited
+    pub rate_limit: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+struct Record {
+    timestamp: DateTime<Local>,
+    lines: Vec<String>,
+}
+
+// token bucket refilled at rate records per second
+struct RateLimiter {
+    rate: u32,
+    tokens: f64,
+    last_refill: time::Instant,
+    dropped: u64,
+}
+
+struct OutputWriter {
+    settings: OutputSettings,
+    source: String,
+    pid: u32,
+    stream: Stream,
+    limiter: RateLimiter,
+    // lines the reader dropped on a full backlog
+    overflow: Arc<AtomicU64>,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown output format: {}", s)),
+        }
+    }
+}
+
+impl Stream {
+    fn name(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last_refill: time::Instant::now(),
+            dropped: 0,
+        }
+    }
+
+    fn allow(&mut self) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        let now = time::Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            self.dropped += 1;
+            false
+        }
+    }
+}
+
+impl OutputWriter {
+    fn format(&self, timestamp: DateTime<Local>, message: &str) -> String {
+        match self.settings.format {
+            OutputFormat::Text => format!(
+                "[{} {} {}] {}",
+                self.source,
+                self.pid,
+                self.stream.name(),
+                message
+            ),
+            OutputFormat::Json => json!({
+                "timestamp": timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+                "source": self.source,
+                "pid": self.pid,
+                "stream": self.stream.name(),
+                "message": message,
+            })
+            .to_string(),
+        }
+    }
+
+    fn write(&mut self, record: Record) {
+        if !self.limiter.allow() {
+            return;
+        }
+        let mut out = String::new();
+        let overflow = self.overflow.swap(0, Ordering::Relaxed);
+        if overflow > 0 {
+            let notice = format!("gasket: {} lines dropped (backlog full)", overflow);
+            out.push_str(&self.format(Local::now(), &notice));
+            out.push('\n');
+        }
+        if self.limiter.dropped > 0 {
+            let notice = format!(
+                "gasket: {} lines dropped (rate limit)",
+                self.limiter.dropped
+            );
+            out.push_str(&self.format(Local::now(), &notice));
+            out.push('\n');
+            self.limiter.dropped = 0;
+        }
+        out.push_str(&self.format(record.timestamp, &record.lines.join("\n")));
+        out.push('\n');
+        let _ = match self.stream {
+            Stream::Stdout => std::io::stdout().lock().write_all(out.as_bytes()),
+            Stream::Stderr => std::io::stderr().lock().write_all(out.as_bytes()),
+        };
+    }
+
+    // merges continuation lines into records until the stream closes
+    fn run(mut self, lines: mpsc::Receiver<String>) {
+        let flush = time::Duration::from_millis(MULTILINE_FLUSH_MS);
+        let mut pending: Option<Record> = None;
+        loop {
+            let line = if pending.is_some() {
+                match lines.recv_timeout(flush) {
+                    Ok(line) => Some(line),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            } else {
+                match lines.recv() {
+                    Ok(line) => Some(line),
+                    Err(_) => break,
+                }
+            };
+            let line = match line {
+                Some(line) => line,
+                None => {
+                    if let Some(record) = pending.take() {
+                        self.write(record);
+                    }
+                    continue;
+                }
+            };
+            let continuation = match (&self.settings.multiline, pending.as_ref()) {
+                (Some(re), Some(record)) => {
+                    re.is_match(&line) && record.lines.len() < MULTILINE_MAX_LINES
+                }
+                _ => false,
+            };
+            if continuation {
+                if let Some(record) = pending.as_mut() {
+                    record.lines.push(line);
+                }
+                continue;
+            }
+            if let Some(record) = pending.take() {
+                self.write(record);
+            }
+            let record = Record {
+                timestamp: Local::now(),
+                lines: vec![line],
+            };
+            if self.settings.multiline.is_some() {
+                pending = Some(record);
+            } else {
+                self.write(record);
+            }
+        }
+        if let Some(record) = pending.take() {
+            self.write(record);
+        }
+    }
+}
+
+// reads a line without its \n (or \r\n) keeping at most max bytes, the rest of a longer line
+// is skipped. None at the end of the stream.
+fn read_line(reader: &mut impl BufRead, max: usize) -> std::io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let mut read_any = false;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        read_any = true;
+        let (chunk, end) = match buf.iter().position(|b| *b == b'\n') {
+            Some(i) => (&buf[..i], Some(i + 1)),
+            None => (buf, None),
+        };
+        let room = max.saturating_sub(line.len());
+        line.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        let consumed = end.unwrap_or(buf.len());
+        reader.consume(consumed);
+        if end.is_some() {
+            break;
+        }
+    }
+    if !read_any {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+// reads a child stream on its own threads until the child closes it
+pub fn capture(
+    settings: &OutputSettings,
+    source: &str,
+    pid: u32,
+    stream: Stream,
+    reader: impl Read + Send + 'static,
+) {
+    let (sender, receiver) = mpsc::sync_channel(BACKLOG_LINES);
+    let overflow = Arc::new(AtomicU64::new(0));
+    let writer = OutputWriter {
+        limiter: RateLimiter::new(settings.rate_limit),
+        settings: settings.clone(),
+        source: source.to_string(),
+        pid,
+        stream,
+        overflow: overflow.clone(),
+    };
+    thread::spawn(move || writer.run(receiver));
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        while let Ok(Some(line)) = read_line(&mut reader, MAX_LINE_BYTES) {
+            match sender.try_send(String::from_utf8_lossy(&line).into_owned()) {
+                Ok(()) => {}
+                Err(mpsc::TrySendError::Full(_)) => {
+                    overflow.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(input: &[u8], max: usize) -> Vec<String> {
+        let mut reader = BufReader::with_capacity(4, input);
+        let mut lines = Vec::new();
+        while let Some(line) = read_line(&mut reader, max).unwrap() {
+            lines.push(String::from_utf8(line).unwrap());
+        }
+        lines
+    }
+
+    #[test]
+    fn splits_lines() {
+        assert_eq!(
+            lines(b"one\r\ntwo\n\nthree", 100),
+            ["one", "two", "", "three"]
+        );
+        assert!(lines(b"", 100).is_empty());
+    }
+
+    #[test]
+    fn cuts_long_lines() {
+        assert_eq!(
+            lines(b"0123456789abcdef\nshort\n", 10),
+            ["0123456789", "short"]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use chrono::Duration;
+use log::info;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+// Health checks: probe the child application and publish its readiness
+// Http: GET on a path of the upstream port, any 2xx/3xx is healthy
+// Tcp: a connection to the upstream port can be established
+// Exec: a command run with sh -c exits with 0, it gets the probed port in GASKET_PROBE_PORT
+// None: the child is always considered ready
+// The child becomes ready after healthy_threshold consecutive successes and is marked down
+// after unhealthy_threshold consecutive failures. Until it is ready for the first time it is
+// probed every STARTUP_INTERVAL so traffic flows as soon as the app listens.
+pub const STARTUP_INTERVAL_MS: i64 = 250;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckKind {
+    Http,
+    Tcp,
+    Exec,
+    None,
+}
+
+#[derive(Clone, Debug)]
+pub struct HealthCheckSettings {
+    // what is checked, used in the logs
+    pub name: String,
+    pub kind: HealthCheckKind,
+    pub path: String,
+    pub command: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub healthy_threshold: u32,
+    pub unhealthy_threshold: u32,
+    // how long requests wait for a child that is not ready before getting a 503
+    pub hold: Duration,
+}
+
+// Readiness of the child, shared with the request handlers
+pub struct Readiness {
+    sender: watch::Sender<bool>,
+    receiver: watch::Receiver<bool>,
+    hold: Duration,
+    name: String,
+}
+
+pub struct HealthChecker {
+    settings: HealthCheckSettings,
+    // the upstream port can change with a rolling restart
+    port: Box<dyn Fn() -> u16>,
+    readiness: Arc<Readiness>,
+}
+
+impl FromStr for HealthCheckKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(HealthCheckKind::Http),
+            "tcp" => Ok(HealthCheckKind::Tcp),
+            "exec" => Ok(HealthCheckKind::Exec),
+            "none" => Ok(HealthCheckKind::None),
+            _ => Err(format!("unknown health check: {}", s)),
+        }
+    }
+}
+
+impl Readiness {
+    pub fn new(name: &str, ready: bool, hold: Duration) -> Self {
+        let (sender, receiver) = watch::channel(ready);
+        Self {
+            sender,
+            receiver,
+            hold,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    pub fn set(&self, ready: bool) {
+        if self.is_ready() != ready {
+            info!("{} {}", self.name, if ready { "ready" } else { "down" });
+            let _ = self.sender.send(ready);
+        }
+    }
+
+    // waits until the child is ready
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // waits up to the hold time for the child to be ready
+    pub async fn wait_ready(&self) -> bool {
+        if self.is_ready() {
+            return true;
+        }
+        let hold = self.hold.to_std().unwrap_or_default();
+        let _ = actix_web::rt::time::timeout(hold, self.wait()).await;
+        self.is_ready()
+    }
+}
+
+impl HealthChecker {
+    // starts probing the child on port, returns the readiness it keeps up to date
+    pub fn start(
+        settings: HealthCheckSettings,
+        port: impl Fn() -> u16 + 'static,
+    ) -> Arc<Readiness> {
+        if settings.kind == HealthCheckKind::None {
+            return Arc::new(Readiness::new(&settings.name, true, settings.hold));
+        }
+        info!(
+            "Health check: {} {:?} every {}s",
+            settings.name,
+            settings.kind,
+            settings.interval.num_seconds()
+        );
+        let readiness = Arc::new(Readiness::new(&settings.name, false, settings.hold));
+        let checker = Self {
+            settings,
+            port: Box::new(port),
+            readiness: readiness.clone(),
+        };
+        actix_web::rt::spawn(checker.run());
+        readiness
+    }
+
+    async fn run(self) {
+        let mut successes = 0;
+        let mut failures = 0;
+        let mut started = false;
+        loop {
+            if probe(&self.settings, (self.port)()).await {
+                successes += 1;
+                failures = 0;
+                if successes >= self.settings.healthy_threshold {
+                    started = true;
+                    self.readiness.set(true);
+                }
+            } else {
+                failures += 1;
+                successes = 0;
+                if failures >= self.settings.unhealthy_threshold {
+                    self.readiness.set(false);
+                }
+            }
+
+            let interval = if started {
+                self.settings.interval
+            } else {
+                std::cmp::min(
+                    self.settings.interval,
+                    Duration::milliseconds(STARTUP_INTERVAL_MS),
+                )
+            };
+            actix_web::rt::time::sleep(interval.to_std().unwrap_or_default()).await;
+        }
+    }
+}
+
+// a single check of the child on port
+pub async fn probe(settings: &HealthCheckSettings, port: u16) -> bool {
+    let timeout = settings.timeout.to_std().unwrap_or_default();
+    match settings.kind {
+        HealthCheckKind::Http => {
+            let url = format!("http://127.0.0.1:{}{}", port, settings.path);
+            match awc::Client::new().get(url).timeout(timeout).send().await {
+                Ok(res) => res.status().is_success() || res.status().is_redirection(),
+                Err(_) => false,
+            }
+        }
+        HealthCheckKind::Tcp => {
+            let connect = actix_web::rt::net::TcpStream::connect(("127.0.0.1", port));
+            matches!(
+                actix_web::rt::time::timeout(timeout, connect).await,
+                Ok(Ok(_))
+            )
+        }
+        HealthCheckKind::Exec => {
+            let mut command = std::process::Command::new("sh");
+            command
+                .arg("-c")
+                .arg(&settings.command)
+                .env("GASKET_PROBE_PORT", port.to_string());
+            let (child, exited) = match crate::reaper::spawn(&mut command) {
+                Ok(spawned) => spawned,
+                Err(_) => return false,
+            };
+            match actix_web::rt::time::timeout(timeout, exited).await {
+                Ok(Ok(status)) => status.success(),
+                Ok(Err(_)) => false,
+                Err(_) => {
+                    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGKILL) };
+                    false
+                }
+            }
+        }
+        HealthCheckKind::None => true,
+    }
+}
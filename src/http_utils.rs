@@ -1,9 +1,20 @@
-use actix_web::{HttpRequest, HttpResponse};
-use std::sync::{Arc, Mutex};
+use crate::stability_patterns::{
+    ConcurrencyOutcome, ThrottleDecision, ThrottlerSettings, ThrottlingKey,
+};
+use crate::telemetry::{AttributeValue, Trace};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use awc::error::SendRequestError;
+use chrono::Local;
+use log::info;
+use std::sync::Arc;
 use uuid::Uuid;
 
 const HEADER_X_FORWARDED_FOR: &str = "x-forwarded-for";
-const HEADER_X_GASKET_REQUEST_ID: &str = "x-gasket-request-id";
+const HEADER_RATELIMIT_LIMIT: &str = "ratelimit-limit";
+const HEADER_RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+const HEADER_RATELIMIT_RESET: &str = "ratelimit-reset";
+const HEADER_RETRY_AFTER: &str = "retry-after";
 
 // const HOP_BY_HOP_HEADERS: Vec<&str> = vec![
 //     "connection",
@@ -18,35 +29,102 @@ const HEADER_X_GASKET_REQUEST_ID: &str = "x-gasket-request-id";
 // ];
 
 // TODO: prune hop by hop headers
-// TODO: add throttle info
 // TODO: consider adding mTLS info
-pub struct Proxy {
-    pub id: Uuid, // request unique id
+pub struct Proxy {}
+
+// id stamped on the request, sent to the application and returned to the client
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+// An id received in one of the accepted headers (checked in order) is kept when it is
+// at most max_length characters of [A-Za-z0-9-_.:/+=@], a new uuid is generated otherwise.
+// The id is sent upstream and returned in header.
+#[derive(Clone, Debug)]
+pub struct RequestIdSettings {
+    pub accepted_headers: Vec<String>,
+    pub header: String,
+    pub max_length: usize,
+}
+
+impl RequestIdSettings {
+    fn valid(&self, id: &str) -> bool {
+        !id.is_empty()
+            && id.len() <= self.max_length
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_.:/+=@".contains(&b))
+    }
+
+    // id of the request, resolved from the headers the first time
+    pub fn request_id(&self, req: &HttpRequest) -> String {
+        if let Some(id) = req.extensions().get::<RequestId>() {
+            return id.0.clone();
+        }
+        let mut id = None;
+        for header in self.accepted_headers.iter() {
+            if let Some(value) = req.headers().get(header.as_str()) {
+                match value.to_str() {
+                    Ok(value) if self.valid(value) => {
+                        id = Some(value.to_string());
+                        break;
+                    }
+                    _ => info!("ignoring invalid request id in {}", header),
+                }
+            }
+        }
+        let id = id.unwrap_or_else(|| Uuid::new_v4().to_string());
+        req.extensions_mut().insert(RequestId(id.clone()));
+        id
+    }
+}
+
+// what happened upstream, reported by the access log
+#[derive(Clone, Copy, Debug)]
+pub struct UpstreamStats {
+    pub attempts: u32,
+    // time spent waiting on the application, over all attempts
+    pub latency: chrono::Duration,
+}
+
+// seconds rounded up, as expected by Retry-After and RateLimit-Reset
+fn ceil_seconds(d: chrono::Duration) -> i64 {
+    (d.num_milliseconds().max(0) + 999) / 1000
 }
 
 impl Proxy {
-    pub async fn forward(
-        req: HttpRequest,
-        body: actix_web::web::Bytes,
-        url: &url::Url,
-        sp: Arc<Mutex<crate::stability_patterns::StabilityPatterns>>,
-    ) -> actix_web::Result<actix_web::HttpResponse> {
-        // create an exponential backoff for the URL Path of ot does not exists
-        let backoff_key = req.uri().path().to_string().clone();
-        sp.lock().unwrap().exponential_backoff(backoff_key.clone());
-        // // connector w/ timeout
-
-        let to = sp
-            .lock()
-            .unwrap()
-            .current_timeout(backoff_key.clone())
-            .to_std()
-            .unwrap();
-        // let connector = awc::Connector::new()
-        //     // This is the timeout setting for connector. It's 1 second by default
-        //     .timeout(to.clone())
-        //     .finish();
+    // throttler key for a request, requests missing an identity or the configured
+    // header fall back to the client ip
+    fn throttle_key(req: &HttpRequest, route_key: &str, settings: &ThrottlerSettings) -> String {
+        let client_ip = match req.peer_addr() {
+            Some(addr) => addr.ip().to_string(),
+            None => "unknown".to_string(),
+        };
+        match settings.key {
+            ThrottlingKey::Route => format!("route:{}", route_key),
+            ThrottlingKey::ClientIp => format!("ip:{}", client_ip),
+            ThrottlingKey::Identity => {
+                match req.extensions().get::<crate::tls_utils::PeerIdentity>() {
+                    Some(identity) => format!("identity:{}", identity.0),
+                    None => format!("ip:{}", client_ip),
+                }
+            }
+            ThrottlingKey::Header => match req
+                .headers()
+                .get(settings.header.as_str())
+                .and_then(|v| v.to_str().ok())
+            {
+                Some(value) => format!("header:{}", value),
+                None => format!("ip:{}", client_ip),
+            },
+        }
+    }
 
+    fn upstream_request(
+        req: &HttpRequest,
+        url: &url::Url,
+        request_id: (&str, &str),
+        timeout: std::time::Duration,
+    ) -> awc::ClientRequest {
         let client = awc::Client::new();
         let mut new_url = url.clone();
 
@@ -54,30 +132,233 @@ impl Proxy {
         new_url.set_query(req.uri().query());
         let mut client_req = client
             .request_from(new_url.as_str(), req.head())
-            .no_decompress();
-
-        // timeout increases on failures to avoid slowdowns
-        client_req = client_req.timeout(to);
+            .no_decompress()
+            .timeout(timeout);
 
         client_req = if let Some(addr) = req.peer_addr() {
             client_req.append_header((HEADER_X_FORWARDED_FOR, format!("{}", addr.ip())))
         } else {
             client_req
         };
-        // stamp unique id
-        let id = Uuid::new_v4();
-        client_req = client_req.append_header((HEADER_X_GASKET_REQUEST_ID, id.to_string()));
+        // replaces the incoming id when the header is the same
+        client_req.insert_header(request_id)
+    }
+
+    fn ratelimit_headers(hrb: &mut HttpResponseBuilder, decision: &ThrottleDecision) {
+        hrb.insert_header((HEADER_RATELIMIT_LIMIT, decision.limit.to_string()));
+        hrb.insert_header((HEADER_RATELIMIT_REMAINING, decision.remaining.to_string()));
+        hrb.insert_header((
+            HEADER_RATELIMIT_RESET,
+            ceil_seconds(decision.reset).to_string(),
+        ));
+    }
+
+    // the throttle decision, made before anything else is done for the request, even before
+    // it is held for readiness. Err: the 429 answer of a rejected request
+    pub fn throttle(
+        req: &HttpRequest,
+        sp: &crate::stability_patterns::StabilityPatterns,
+        metrics: &crate::metrics::Metrics,
+        route_key: &str,
+    ) -> Result<Option<ThrottleDecision>, HttpResponse> {
+        let decision = match sp.throttler_settings.as_ref() {
+            Some(settings) => sp.throttle(Proxy::throttle_key(req, route_key, settings)),
+            None => None,
+        };
+        match decision {
+            Some(decision) if !decision.allowed => {
+                metrics.rejected(route_key, "throttled");
+                Err(Proxy::throttled(&decision))
+            }
+            decision => Ok(decision),
+        }
+    }
+
+    // 429 for a request the throttler rejected
+    fn throttled(decision: &ThrottleDecision) -> HttpResponse {
+        let mut hrb = HttpResponse::TooManyRequests();
+        Proxy::ratelimit_headers(&mut hrb, decision);
+        hrb.insert_header((
+            HEADER_RETRY_AFTER,
+            ceil_seconds(decision.retry_after).to_string(),
+        ));
+        hrb.finish()
+    }
+
+    // throttle_decision: the request was let through by Proxy::throttle
+    #[allow(clippy::too_many_arguments)]
+    pub async fn forward(
+        req: HttpRequest,
+        body: actix_web::web::Bytes,
+        url: &url::Url,
+        sp: Arc<crate::stability_patterns::StabilityPatterns>,
+        metrics: &crate::metrics::Metrics,
+        route_key: &str,
+        request_id: &RequestIdSettings,
+        throttle_decision: Option<ThrottleDecision>,
+    ) -> actix_web::Result<actix_web::HttpResponse> {
+        let id = request_id.request_id(&req);
 
-        let mut res = match client_req.send_body(body).await {
+        if !sp.circuit_closed(route_key.to_string()) {
+            metrics.rejected(route_key, "circuit_open");
+            return Ok(HttpResponse::ServiceUnavailable()
+                .insert_header((HEADER_RETRY_AFTER, "1"))
+                .body("circuit open"));
+        }
+
+        // live requests feed the retry budget of their route
+        sp.deposit_retry_budget(route_key.to_string());
+        let retry_settings = sp.retry_settings.as_ref();
+        let concurrency_limiter = sp.concurrency_limiter.as_ref();
+        let timeout = sp.timeout;
+        // only idempotent (configured) methods are retried
+        let (max_attempts, deadline) = match retry_settings {
+            Some(settings) if settings.methods.iter().any(|m| m == req.method().as_str()) => {
+                (settings.max_attempts.max(1), settings.deadline)
+            }
+            _ => (1, timeout),
+        };
+
+        let started = Local::now();
+        let mut attempt = 1;
+        let mut upstream_latency = chrono::Duration::zero();
+        let mut backoff = sp.exponential_backoff();
+        let result = loop {
+            // attempts never outlive the overall deadline
+            let remaining = deadline - (Local::now() - started);
+            let to = std::cmp::min(timeout, remaining)
+                .to_std()
+                .unwrap_or_default();
+
+            // wait for an upstream slot, shed the request when none frees up in time
+            let permit = match concurrency_limiter {
+                Some(limiter) => match limiter.acquire().await {
+                    Some(permit) => Some(permit),
+                    None => {
+                        info!("[{}] concurrency limit reached, shedding {}", id, route_key);
+                        metrics.rejected(route_key, "concurrency");
+                        return Ok(HttpResponse::ServiceUnavailable()
+                            .insert_header((HEADER_RETRY_AFTER, "1"))
+                            .finish());
+                    }
+                },
+                None => None,
+            };
+
+            // client span per attempt, under the server span of the request
+            let trace = req.extensions().get::<Trace>().cloned();
+            let mut span = trace
+                .as_ref()
+                .map(|t| t.client_span(format!("{} {}", req.method(), route_key)));
+            let mut client_req = Proxy::upstream_request(&req, url, (&request_id.header, &id), to);
+            if let (Some(trace), Some(span)) = (trace.as_ref(), span.as_mut()) {
+                trace.inject(span, client_req.headers_mut());
+                span.set_attribute(
+                    "http.method",
+                    AttributeValue::String(req.method().to_string()),
+                );
+                span.set_attribute(
+                    "http.url",
+                    AttributeValue::String(client_req.get_uri().to_string()),
+                );
+                span.set_attribute("gasket.attempt", AttributeValue::Int(attempt as i64));
+            }
+            let sent = Local::now();
+            let result = client_req.send_body(body.clone()).await;
+            upstream_latency = upstream_latency + (Local::now() - sent);
+            if let Some(mut span) = span {
+                match &result {
+                    Ok(res) => {
+                        let status = res.status();
+                        span.set_attribute(
+                            "http.status_code",
+                            AttributeValue::Int(status.as_u16() as i64),
+                        );
+                        if status.is_server_error() {
+                            span.set_error(status.to_string());
+                        }
+                    }
+                    Err(e) => span.set_error(e.to_string()),
+                }
+                span.end();
+            }
+
+            if let Err(e) = &result {
+                metrics.upstream_error(
+                    route_key,
+                    match e {
+                        SendRequestError::Connect(_) => "connect",
+                        SendRequestError::Timeout => "timeout",
+                        _ => "other",
+                    },
+                );
+            }
+
+            // 5xx and transport errors count against the circuit breaker
+            match &result {
+                Ok(res) if !res.status().is_server_error() => sp.reset(route_key.to_string()),
+                _ => {
+                    sp.trip(route_key.to_string());
+                }
+            }
+
+            if let Some(permit) = permit {
+                permit.release(match &result {
+                    Ok(res) if res.status() == StatusCode::SERVICE_UNAVAILABLE => {
+                        ConcurrencyOutcome::Dropped
+                    }
+                    Ok(_) => ConcurrencyOutcome::Success,
+                    Err(SendRequestError::Timeout) => ConcurrencyOutcome::Dropped,
+                    Err(_) => ConcurrencyOutcome::Ignore,
+                });
+            }
+
+            let retryable = match (&result, retry_settings) {
+                (Ok(res), Some(settings)) => settings.status_codes.contains(&res.status().as_u16()),
+                (Err(SendRequestError::Connect(_)), _) | (Err(SendRequestError::Timeout), _) => {
+                    true
+                }
+                _ => false,
+            };
+            if !retryable || attempt >= max_attempts || !sp.circuit_closed(route_key.to_string()) {
+                break result;
+            }
+
+            let delay = match backoff.as_mut() {
+                Some(b) => b.next(),
+                None => break result,
+            };
+            if (Local::now() - started) + delay >= deadline {
+                info!("[{}] retry deadline reached for {}", id, route_key);
+                metrics.retries_exhausted(route_key, "deadline");
+                break result;
+            }
+            if !sp.withdraw_retry_budget(route_key.to_string()) {
+                metrics.retries_exhausted(route_key, "budget");
+                break result;
+            }
+
+            info!(
+                "[{}] retrying {} {} (attempt {}) in {}ms",
+                id,
+                req.method(),
+                route_key,
+                attempt + 1,
+                delay.num_milliseconds()
+            );
+            metrics.retry(route_key);
+            actix_web::rt::time::sleep(delay.to_std().unwrap_or_default()).await;
+            attempt += 1;
+        };
+
+        req.extensions_mut().insert(UpstreamStats {
+            attempts: attempt,
+            latency: upstream_latency,
+        });
+
+        let mut res = match result {
             Ok(res) => res,
             Err(e) => {
-                // increments timeout
-                let _ = sp
-                    .lock()
-                    .unwrap()
-                    .next_backoff(backoff_key)
-                    .to_std()
-                    .unwrap();
                 return Ok(HttpResponse::InternalServerError().body(format!("{}", e)));
             }
         };
@@ -90,9 +371,94 @@ impl Proxy {
         {
             hrb.append_header((header_name.clone(), header_value.clone()));
         }
-        hrb.append_header((HEADER_X_GASKET_REQUEST_ID, id.to_string()));
+        if let Some(decision) = throttle_decision.as_ref() {
+            Proxy::ratelimit_headers(&mut hrb, decision);
+        }
         let res_a = hrb.message_body(res_body).unwrap();
 
-        return Ok(res_a);
+        Ok(res_a)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn request_ids() -> RequestIdSettings {
+        RequestIdSettings {
+            accepted_headers: vec!["x-request-id".to_string(), "x-correlation-id".to_string()],
+            header: "x-request-id".to_string(),
+            max_length: 16,
+        }
+    }
+
+    #[test]
+    fn valid_request_ids() {
+        let settings = request_ids();
+        assert!(settings.valid("abc-123"));
+        assert!(settings.valid("a_b.c:d/e+f=g@h"));
+        assert!(settings.valid(&"a".repeat(16)));
+        assert!(!settings.valid(""));
+        assert!(!settings.valid(&"a".repeat(17)));
+        // nothing that could split a header or a log line
+        assert!(!settings.valid("abc\r\nx-evil: 1"));
+        assert!(!settings.valid("abc\n"));
+        assert!(!settings.valid("abc\t"));
+        assert!(!settings.valid("a b"));
+        assert!(!settings.valid("\"quoted\""));
+        assert!(!settings.valid("caf\u{e9}"));
+    }
+
+    #[test]
+    fn request_id_headers() {
+        let settings = request_ids();
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("x-correlation-id", "from-client"))
+            .to_http_request();
+        assert_eq!(settings.request_id(&req), "from-client");
+
+        // the first accepted header with a valid id wins
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("x-request-id", "bad id"))
+            .insert_header(("x-correlation-id", "good-id"))
+            .to_http_request();
+        assert_eq!(settings.request_id(&req), "good-id");
+
+        // without a valid id one is generated, then kept for the request
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("x-request-id", "much-too-long-request-id"))
+            .to_http_request();
+        let id = settings.request_id(&req);
+        assert!(Uuid::parse_str(&id).is_ok());
+        assert_eq!(settings.request_id(&req), id);
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        assert_ne!(settings.request_id(&req), id);
+    }
+
+    #[test]
+    fn seconds_round_up() {
+        assert_eq!(ceil_seconds(Duration::zero()), 0);
+        assert_eq!(ceil_seconds(Duration::milliseconds(1)), 1);
+        assert_eq!(ceil_seconds(Duration::milliseconds(1000)), 1);
+        assert_eq!(ceil_seconds(Duration::milliseconds(1001)), 2);
+        assert_eq!(ceil_seconds(Duration::milliseconds(-5)), 0);
+    }
+
+    #[test]
+    fn throttled_headers() {
+        let response = Proxy::throttled(&ThrottleDecision {
+            allowed: false,
+            limit: 4,
+            remaining: 0,
+            reset: Duration::milliseconds(500),
+            retry_after: Duration::milliseconds(1500),
+        });
+        assert_eq!(response.status(), 429);
+        let header = |name: &str| response.headers().get(name).unwrap().to_str().unwrap();
+        assert_eq!(header(HEADER_RATELIMIT_LIMIT), "4");
+        assert_eq!(header(HEADER_RATELIMIT_REMAINING), "0");
+        assert_eq!(header(HEADER_RATELIMIT_RESET), "1");
+        assert_eq!(header(HEADER_RETRY_AFTER), "2");
     }
 }
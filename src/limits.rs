@@ -0,0 +1,410 @@
+use log::info;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+// Resource limits of the supervised processes:
+// - rlimits (open files, core size, processes, address space) set before exec
+// - a cgroup v2 per process with memory.max, cpu.max and pids.max, created below the cgroup
+//   gasket started in. cgroup v2 only lets leaf cgroups hold processes once controllers are
+//   enabled, so gasket first moves itself to a gasket leaf. Every step is best effort: a read
+//   only cgroupfs (no delegation, cgroups v1) means no limits, not a failure.
+// A process killed by the OOM killer is told apart from other SIGKILLs with the oom_kill
+// counter of memory.events, in its own cgroup or, without one, in gasket's (hierarchical).
+// The cgroups are created at startup, before gasket drops its privileges (--drop-privileges).
+// Its processes are then started unprivileged: the cgroup.procs files they have to write to
+// join their cgroup are given to the user, and gasket raises its own hard rlimits to the
+// configured ones since its processes can only lower them.
+
+// a limit: soft[:hard], numbers with an optional K/M/G suffix or unlimited
+#[derive(Clone, Copy, Debug)]
+pub struct Rlimit {
+    pub soft: libc::rlim_t,
+    pub hard: libc::rlim_t,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CgroupLimits {
+    pub memory_max: Option<u64>,
+    // cores
+    pub cpu_max: Option<f64>,
+    pub pids_max: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    // RLIMIT_* resources, their type differs between libcs
+    pub rlimits: Vec<(libc::c_int, Rlimit)>,
+    pub cgroup: CgroupLimits,
+    // the cgroup of each process, by name
+    pub cgroups: BTreeMap<String, ProcessCgroup>,
+}
+
+// the cgroup of a supervised process
+#[derive(Clone, Debug)]
+pub struct ProcessCgroup {
+    path: PathBuf,
+    procs: CString,
+}
+
+const CPU_PERIOD_US: u64 = 100_000;
+
+// a size: 512, 64K, 256M, 2G
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, ' '),
+    };
+    let shift = match unit {
+        ' ' => 0,
+        'K' => 10,
+        'M' => 20,
+        'G' => 30,
+        _ => return Err(format!("invalid size: {}", s)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size: {}", s))
+}
+
+impl FromStr for Rlimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = |v: &str| match v {
+            "unlimited" => Ok(libc::RLIM_INFINITY),
+            v => parse_size(v),
+        };
+        let (soft, hard) = match s.split_once(':') {
+            Some((soft, hard)) => (value(soft)?, value(hard)?),
+            None => (value(s)?, value(s)?),
+        };
+        if soft > hard {
+            return Err(format!("soft limit above the hard limit: {}", s));
+        }
+        Ok(Rlimit { soft, hard })
+    }
+}
+
+// where cgroup2 is mounted, /sys/fs/cgroup or /sys/fs/cgroup/unified (hybrid)
+fn mount_point() -> Option<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        let (mount, fs_type) = line.split_once(" - ")?;
+        if !fs_type.starts_with("cgroup2 ") {
+            return None;
+        }
+        mount.split_whitespace().nth(4).map(PathBuf::from)
+    })
+}
+
+// the cgroup v2 gasket started in, before it moved to its leaf
+pub fn base() -> Option<&'static Path> {
+    static BASE: OnceLock<Option<PathBuf>> = OnceLock::new();
+    BASE.get_or_init(|| {
+        let cgroup = fs::read_to_string("/proc/self/cgroup").ok()?;
+        let path = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+        Some(mount_point()?.join(path.trim_start_matches('/')))
+    })
+    .as_deref()
+}
+
+// the root cgroup holds every process of the host, or of the container with a cgroup namespace
+pub fn is_root(path: &Path) -> bool {
+    mount_point().as_deref() == Some(path)
+}
+
+// every process of a cgroup and of its descendants
+pub fn members(path: &Path) -> Vec<libc::pid_t> {
+    let mut pids: Vec<libc::pid_t> = fs::read_to_string(path.join("cgroup.procs"))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|pid| pid.parse().ok())
+        .collect();
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                pids.extend(members(&entry.path()));
+            }
+        }
+    }
+    pids
+}
+
+fn write(path: &Path, value: &str) -> Result<(), String> {
+    fs::write(path, value).map_err(|e| format!("{} {}: {}", path.display(), value, e))
+}
+
+// oom_kill of memory.events
+fn oom_kills(cgroup: &Path) -> u64 {
+    fs::read_to_string(cgroup.join("memory.events"))
+        .unwrap_or_default()
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// OOM kills so far, of a process cgroup or of gasket's cgroup
+pub fn oom_kill_count(cgroup: Option<&ProcessCgroup>) -> u64 {
+    match cgroup {
+        Some(cgroup) => oom_kills(&cgroup.path),
+        None => base().map(oom_kills).unwrap_or(0),
+    }
+}
+
+// moves gasket to a leaf and enables the controllers for the process cgroups, once
+fn delegate(base: &Path) -> Result<(), String> {
+    static DELEGATED: Mutex<Option<Result<(), String>>> = Mutex::new(None);
+    let mut delegated = DELEGATED.lock().unwrap();
+    if let Some(result) = delegated.as_ref() {
+        return result.clone();
+    }
+    let result = (|| {
+        let leaf = base.join("gasket");
+        if !leaf.exists() {
+            fs::create_dir(&leaf).map_err(|e| format!("{}: {}", leaf.display(), e))?;
+        }
+        write(&leaf.join("cgroup.procs"), &std::process::id().to_string())?;
+        let available = fs::read_to_string(base.join("cgroup.controllers")).unwrap_or_default();
+        for controller in ["memory", "cpu", "pids"] {
+            if available.split_whitespace().any(|c| c == controller) {
+                if let Err(e) = write(
+                    &base.join("cgroup.subtree_control"),
+                    &format!("+{}", controller),
+                ) {
+                    info!("Cgroup: {}", e);
+                }
+            } else {
+                info!("Cgroup: controller {} not available", controller);
+            }
+        }
+        Ok(())
+    })();
+    *delegated = Some(result.clone());
+    result
+}
+
+impl CgroupLimits {
+    pub fn is_empty(&self) -> bool {
+        self.memory_max.is_none() && self.cpu_max.is_none() && self.pids_max.is_none()
+    }
+
+    // the interface files written and their values
+    fn files(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("memory.max", self.memory_max.map(|m| m.to_string())),
+            (
+                "cpu.max",
+                self.cpu_max
+                    .map(|c| format!("{} {}", (c * CPU_PERIOD_US as f64) as u64, CPU_PERIOD_US)),
+            ),
+            ("pids.max", self.pids_max.map(|p| p.to_string())),
+        ]
+        .into_iter()
+        .filter_map(|(file, value)| Some((file, value?)))
+        .collect()
+    }
+
+    // the cgroup of a process with the limits that could be written, None without limits or
+    // when cgroups v2 can't be written
+    pub fn create(&self, name: &str) -> Option<ProcessCgroup> {
+        if self.is_empty() {
+            return None;
+        }
+        let base = match base() {
+            Some(base) => base,
+            None => {
+                info!("Cgroup: no cgroup v2, {} runs without cgroup limits", name);
+                return None;
+            }
+        };
+        if let Err(e) = delegate(base) {
+            info!("Cgroup: {}, {} runs without cgroup limits", e, name);
+            return None;
+        }
+        let name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = base.join(format!("process-{}", name));
+        if !path.exists() {
+            if let Err(e) = fs::create_dir(&path) {
+                info!(
+                    "Cgroup: {}: {}, {} runs without cgroup limits",
+                    path.display(),
+                    e,
+                    name
+                );
+                return None;
+            }
+        }
+        for (file, value) in self.files() {
+            match write(&path.join(file), &value) {
+                Ok(()) => info!("Cgroup: {} {} {}", name, file, value),
+                Err(e) => info!("Cgroup: {}", e),
+            }
+        }
+        ProcessCgroup::new(path)
+    }
+}
+
+impl ProcessCgroup {
+    pub fn new(path: PathBuf) -> Option<Self> {
+        let procs = CString::new(path.join("cgroup.procs").to_string_lossy().as_bytes()).ok()?;
+        Some(ProcessCgroup { path, procs })
+    }
+
+    // lets uid move processes to this cgroup: moving needs write access to the cgroup.procs
+    // of the cgroup and of the common ancestor, gasket's cgroup
+    fn delegate(&self, uid: libc::uid_t, gid: libc::gid_t) -> Result<(), String> {
+        let mut files = vec![self.path.join("cgroup.procs")];
+        files.extend(base().map(|base| base.join("cgroup.procs")));
+        for file in files {
+            std::os::unix::fs::chown(&file, Some(uid), Some(gid))
+                .map_err(|e| format!("{}: {}", file.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Limits {
+    // creates the cgroups of the processes, and when gasket will run as owner (uid, gid) gives
+    // them to it and raises gasket's hard rlimits to the configured ones
+    pub fn prepare(&mut self, names: &[String], owner: Option<(libc::uid_t, libc::gid_t)>) {
+        for name in names {
+            if let Some(cgroup) = self.cgroup.create(name) {
+                if let Some((uid, gid)) = owner {
+                    if let Err(e) = cgroup.delegate(uid, gid) {
+                        info!("Cgroup: {}, {} may not join its cgroup", e, name);
+                    }
+                }
+                self.cgroups.insert(name.clone(), cgroup);
+            }
+        }
+        if owner.is_none() {
+            return;
+        }
+        for (resource, limit) in self.rlimits.iter() {
+            let mut current = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            unsafe {
+                if libc::getrlimit(*resource as _, &mut current) != 0
+                    || current.rlim_max >= limit.hard
+                {
+                    continue;
+                }
+                let raised = libc::rlimit {
+                    rlim_cur: current.rlim_cur,
+                    rlim_max: limit.hard,
+                };
+                if libc::setrlimit(*resource as _, &raised) != 0 {
+                    info!(
+                        "Rlimit: can't raise hard limit {} to {}: {}",
+                        resource,
+                        limit.hard,
+                        std::io::Error::last_os_error()
+                    );
+                }
+            }
+        }
+    }
+
+    // the process joins its cgroup and gets its rlimits before exec
+    pub fn configure(&self, command: &mut Command, cgroup: Option<&ProcessCgroup>) {
+        let rlimits = self.rlimits.clone();
+        let procs = cgroup.map(|c| c.procs.clone());
+        if rlimits.is_empty() && procs.is_none() {
+            return;
+        }
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(procs) = procs.as_ref() {
+                    // 0 is the writing process
+                    let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd < 0 || libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) != 1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    libc::close(fd);
+                }
+                for (resource, limit) in rlimits.iter() {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit.soft,
+                        rlim_max: limit.hard,
+                    };
+                    if libc::setrlimit(*resource as _, &rlimit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64K"), Ok(64 << 10));
+        assert_eq!(parse_size("256m"), Ok(256 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+
+    #[test]
+    fn rlimits() {
+        let limit = |s: &str| s.parse::<Rlimit>().map(|l| (l.soft, l.hard));
+        assert_eq!(limit("1024"), Ok((1024, 1024)));
+        assert_eq!(limit("1024:4096"), Ok((1024, 4096)));
+        assert_eq!(limit("1K:unlimited"), Ok((1024, libc::RLIM_INFINITY)));
+        assert_eq!(
+            limit("unlimited"),
+            Ok((libc::RLIM_INFINITY, libc::RLIM_INFINITY))
+        );
+        assert!(limit("4096:1024").is_err());
+        assert!(limit("unlimited:1024").is_err());
+        assert!(limit("1024:").is_err());
+        assert!(limit("many").is_err());
+    }
+
+    #[test]
+    fn cgroup_files() {
+        assert!(CgroupLimits::default().is_empty());
+        assert!(CgroupLimits::default().files().is_empty());
+        let limits = CgroupLimits {
+            memory_max: Some(512 << 20),
+            cpu_max: Some(0.5),
+            pids_max: Some(64),
+        };
+        assert_eq!(
+            limits.files(),
+            [
+                ("memory.max", "536870912".to_string()),
+                ("cpu.max", "50000 100000".to_string()),
+                ("pids.max", "64".to_string()),
+            ]
+        );
+        let cpu_only = CgroupLimits {
+            cpu_max: Some(2.0),
+            ..Default::default()
+        };
+        assert_eq!(cpu_only.files(), [("cpu.max", "200000 100000".to_string())]);
+    }
+}
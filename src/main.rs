@@ -1,13 +1,30 @@
 use clap::{AppSettings, Clap};
 use log::info;
-use std::env;
 use std::sync::Arc;
 
+mod access_log;
+mod admin;
+mod child_env;
+mod child_output;
+mod health_check;
 mod http_utils;
+mod limits;
+mod metrics;
+mod ports;
+mod privileges;
 mod process_manager;
+mod process_tree;
 mod proxy;
+mod reaper;
+mod rolling_restart;
+mod routes;
 mod server;
+mod sharded_map;
+mod shell_words;
+mod shutdown;
+mod signals;
 mod stability_patterns;
+mod telemetry;
 mod tls_utils;
 
 /*
@@ -20,9 +37,171 @@ mod tls_utils;
 #[clap(name = "gasket")]
 #[clap(setting = AppSettings::ColoredHelp)]
 pub struct GasketOptions {
-    /// command to be executed
-    #[clap(short = 'e', long = "execute", default_value = "")]
-    command: String,
+    /// command to be executed, split with shell quoting ("node 'my server.js'")
+    #[clap(short = 'e', long = "execute")]
+    command: Option<String>,
+
+    /// run the command with /bin/sh -c (pipes, variables, globs...)
+    #[clap(long = "shell")]
+    shell: bool,
+
+    /// command to be executed, exec form: gasket [options] -- cmd arg1 "arg 2"
+    #[clap(last = true)]
+    exec: Vec<String>,
+
+    /// restart policy of the -e command
+    #[clap(long = "restart", default_value = "always", possible_values = &["always", "on-failure", "never"])]
+    restart: process_manager::RestartPolicy,
+
+    /// first delay before restarting a process in milliseconds, doubled on each restart
+    #[clap(long = "restart-backoff-initial", default_value = "1000")]
+    restart_backoff_initial: u64,
+
+    /// max delay before restarting a process in milliseconds
+    #[clap(long = "restart-backoff-max", default_value = "30000")]
+    restart_backoff_max: u64,
+
+    /// restarts allowed per process over --restart-window, 0 is unlimited
+    #[clap(long = "restart-limit", default_value = "5")]
+    restart_limit: u32,
+
+    /// sliding window of the restart budget in seconds
+    #[clap(long = "restart-window", default_value = "60")]
+    restart_window: u64,
+
+    /// once the restart budget is exceeded: exit with the exit code of the process or stay up without it
+    #[clap(long = "restart-exceeded", default_value = "exit", possible_values = &["exit", "stay-up"])]
+    restart_exceeded: process_manager::RestartExceeded,
+
+    /// seconds requests are still served once gasket is asked to stop (readiness already fails)
+    #[clap(long = "prestop-delay", default_value = "0")]
+    prestop_delay: u64,
+
+    /// seconds in-flight requests are given to complete once the server stops accepting connections
+    #[clap(long = "drain-timeout", default_value = "30")]
+    drain_timeout: u64,
+
+    /// signal sent to the processes to stop them, by name or number
+    #[clap(long = "stop-signal", default_value = "SIGTERM")]
+    stop_signal: signals::Signal,
+
+    /// seconds the processes are given to exit after the stop signal before they are killed
+    #[clap(long = "stop-grace-period", default_value = "10")]
+    stop_grace_period: u64,
+
+    /// once the grace period is over, also kill every other process of gasket's cgroup (v2)
+    #[clap(long = "kill-cgroup")]
+    kill_cgroup: bool,
+
+    /// rewrite of forwarded signals FROM:TO (names or numbers), TO 0 drops the signal, e.g. TERM:QUIT.
+    /// SIGTERM, SIGINT and SIGQUIT stop gasket and are not forwarded, the stop signal is rewritten
+    #[clap(long = "signal-rewrite", use_delimiter = true)]
+    signal_rewrites: Vec<signals::SignalRewrite>,
+
+    /// processes signals are forwarded to: the child, its process group or its session
+    #[clap(long = "signal-target", default_value = "group", possible_values = &["child", "group", "session"])]
+    signal_target: signals::SignalTarget,
+
+    /// SIGHUP is forwarded, makes a rolling restart of the proxied process or reloads the TLS certificates
+    #[clap(long = "sighup", default_value = "forward", possible_values = &["forward", "restart", "reload"])]
+    sighup: signals::SighupAction,
+
+    /// seconds the new process of a rolling restart is given to get healthy before it is killed
+    #[clap(long = "rolling-restart-timeout", default_value = "60")]
+    rolling_restart_timeout: u64,
+
+    /// user the processes run as, name or number
+    #[clap(long = "user")]
+    user: Option<String>,
+
+    /// group the processes run as, name or number (default the primary group of --user)
+    #[clap(long = "group")]
+    group: Option<String>,
+
+    /// supplementary groups of the processes, names or numbers (default the groups of --user)
+    #[clap(long = "supplementary-groups", use_delimiter = true)]
+    supplementary_groups: Vec<String>,
+
+    /// gasket becomes --user/--group too once the TLS material is loaded and the listener bound
+    #[clap(long = "drop-privileges")]
+    drop_privileges: bool,
+
+    /// sets no_new_privs on the processes: setuid binaries and file capabilities grant nothing
+    #[clap(long = "no-new-privs")]
+    no_new_privs: bool,
+
+    /// variable set for the processes (KEY=VALUE), ${NAME} is a gasket setting or a variable, repeatable
+    #[clap(
+        long = "env",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        parse(try_from_str = child_env::parse_var)
+    )]
+    env: Vec<(String, String)>,
+
+    /// dotenv file loaded into the environment of the processes, repeatable
+    #[clap(long = "env-file", multiple_occurrences = true, number_of_values = 1)]
+    env_files: Vec<String>,
+
+    /// variables the processes inherit from gasket (NAME or PREFIX_*), every one when empty
+    #[clap(long = "env-allow", use_delimiter = true)]
+    env_allow: Vec<String>,
+
+    /// variables the processes don't inherit from gasket (NAME or PREFIX_*)
+    #[clap(long = "env-deny", use_delimiter = true)]
+    env_deny: Vec<String>,
+
+    /// variables (NAME or PREFIX_*) set from the file NAME_FILE points to, e.g. DB_PASSWORD
+    #[clap(long = "secret-files", use_delimiter = true)]
+    secret_files: Vec<String>,
+
+    /// port gasket listens on (default PORT, or 3000), 0 picks a free port
+    #[clap(long = "listen-port")]
+    listen_port: Option<u16>,
+
+    /// port of the proxied process (default the listen port + 1), 0 picks a free port
+    #[clap(long = "upstream-port")]
+    upstream_port: Option<u16>,
+
+    /// variables giving the upstream port to the proxied process, e.g. PORT,HTTP_PORT,SERVER_PORT
+    #[clap(
+        long = "upstream-port-env",
+        default_value = "PORT",
+        use_delimiter = true
+    )]
+    upstream_port_env: Vec<String>,
+
+    /// open files limit of the processes, soft[:hard] or unlimited
+    #[clap(long = "rlimit-nofile")]
+    rlimit_nofile: Option<limits::Rlimit>,
+
+    /// core file size limit of the processes (512M, unlimited, 0 disables core dumps)
+    #[clap(long = "rlimit-core")]
+    rlimit_core: Option<limits::Rlimit>,
+
+    /// processes limit of the user of the processes
+    #[clap(long = "rlimit-nproc")]
+    rlimit_nproc: Option<limits::Rlimit>,
+
+    /// address space limit of the processes (2G)
+    #[clap(long = "rlimit-as")]
+    rlimit_as: Option<limits::Rlimit>,
+
+    /// memory.max of the cgroup v2 of each process (512M)
+    #[clap(long = "cgroup-memory-max", parse(try_from_str = limits::parse_size))]
+    cgroup_memory_max: Option<u64>,
+
+    /// cpu.max of the cgroup v2 of each process, in cores (0.5)
+    #[clap(long = "cgroup-cpu-max")]
+    cgroup_cpu_max: Option<f64>,
+
+    /// pids.max of the cgroup v2 of each process
+    #[clap(long = "cgroup-pids-max")]
+    cgroup_pids_max: Option<u64>,
+
+    /// JSON file listing the processes to supervise (command, env, cwd, restart, depends_on, health_check, proxy)
+    #[clap(long = "processes")]
+    processes: Option<String>,
 
     /// private key cert
     #[clap(short = 'p', long = "private-key")]
@@ -48,50 +227,609 @@ pub struct GasketOptions {
     #[clap(short = 'r', long = "throttling")]
     throttling_enabled: bool,
 
+    /// throttling algorithm
+    #[clap(long = "throttling-algorithm", default_value = "token-bucket", possible_values = &["token-bucket", "sliding-window"])]
+    throttling_algorithm: stability_patterns::ThrottlingAlgorithm,
+
+    /// what requests are throttled by
+    #[clap(long = "throttling-key", default_value = "route", possible_values = &["route", "ip", "identity", "header"])]
+    throttling_key: stability_patterns::ThrottlingKey,
+
+    /// header used as throttling key when --throttling-key is header
+    #[clap(long = "throttling-header", default_value = "x-api-key")]
+    throttling_header: String,
+
+    /// max requests allowed per throttling window
+    #[clap(long = "throttling-limit", default_value = "100")]
+    throttling_limit: u32,
+
+    /// throttling window in seconds
+    #[clap(long = "throttling-window", default_value = "1")]
+    throttling_window: u64,
+
     /// circuit breaker
     #[clap(short = 'b', long = "circuitbreaker")]
     circuitbreaker_enabled: bool,
 
-    /// exponential backoff
+    /// consecutive upstream errors before the circuit opens
+    #[clap(long = "circuitbreaker-max-trips", default_value = "5")]
+    circuitbreaker_max_trips: u16,
+
+    /// seconds an open circuit waits before letting requests through again
+    #[clap(long = "circuitbreaker-reset-timeout", default_value = "30")]
+    circuitbreaker_reset_timeout: u64,
+
+    /// exponential backoff: retry failed upstream requests
     #[clap(short = 'k', long = "backoff")]
     backoff_enabled: bool,
+
+    /// methods that are retried, defaults to the idempotent ones
+    #[clap(
+        long = "retry-methods",
+        default_value = "GET,HEAD,OPTIONS,PUT,DELETE,TRACE",
+        use_delimiter = true
+    )]
+    retry_methods: Vec<String>,
+
+    /// upstream status codes that are retried
+    #[clap(
+        long = "retry-status",
+        default_value = "502,503,504",
+        use_delimiter = true
+    )]
+    retry_status: Vec<u16>,
+
+    /// max attempts per request, including the first one
+    #[clap(long = "retry-max-attempts", default_value = "3")]
+    retry_max_attempts: u32,
+
+    /// base backoff delay in milliseconds
+    #[clap(long = "retry-base-delay", default_value = "100")]
+    retry_base_delay: u64,
+
+    /// max backoff delay in milliseconds
+    #[clap(long = "retry-max-delay", default_value = "5000")]
+    retry_max_delay: u64,
+
+    /// backoff jitter
+    #[clap(long = "retry-jitter", default_value = "full", possible_values = &["full", "decorrelated"])]
+    retry_jitter: stability_patterns::Jitter,
+
+    /// overall deadline for a request and its retries in milliseconds
+    #[clap(long = "retry-deadline", default_value = "10000")]
+    retry_deadline: u64,
+
+    /// retries allowed as a percentage of the live requests
+    #[clap(long = "retry-budget", default_value = "20")]
+    retry_budget: u32,
+
+    /// retries per second always allowed regardless of the retry budget
+    #[clap(long = "retry-budget-min", default_value = "3")]
+    retry_budget_min: u32,
+
+    /// max in-flight requests to the upstream, 0 disables concurrency limiting
+    #[clap(long = "concurrency-limit", default_value = "0")]
+    concurrency_limit: u32,
+
+    /// how the concurrency limit is adjusted
+    #[clap(long = "concurrency-mode", default_value = "fixed", possible_values = &["fixed", "aimd", "gradient"])]
+    concurrency_mode: stability_patterns::ConcurrencyMode,
+
+    /// lower bound for the adaptive concurrency limit
+    #[clap(long = "concurrency-min-limit", default_value = "1")]
+    concurrency_min_limit: u32,
+
+    /// upper bound for the adaptive concurrency limit
+    #[clap(long = "concurrency-max-limit", default_value = "200")]
+    concurrency_max_limit: u32,
+
+    /// requests allowed to wait for a free slot, the rest is shed with 503
+    #[clap(long = "concurrency-queue", default_value = "100")]
+    concurrency_queue: u32,
+
+    /// max time a request waits for a free slot in milliseconds
+    #[clap(long = "concurrency-queue-timeout", default_value = "1000")]
+    concurrency_queue_timeout: u64,
+
+    /// route template mapping paths to a stable key: /users/{id}, /static/*, ~regex, name=pattern
+    #[clap(long = "route", multiple_occurrences = true, number_of_values = 1)]
+    routes: Vec<String>,
+
+    /// key used for paths not matching any --route
+    #[clap(long = "route-other", default_value = "other")]
+    route_other: String,
+
+    /// health check run against the child
+    #[clap(long = "health-check", default_value = "tcp", possible_values = &["http", "tcp", "exec", "none"])]
+    health_check: health_check::HealthCheckKind,
+
+    /// path requested by the http health check
+    #[clap(long = "health-check-path", default_value = "/")]
+    health_check_path: String,
+
+    /// command run (with sh -c) by the exec health check
+    #[clap(long = "health-check-command", default_value = "true")]
+    health_check_command: String,
+
+    /// seconds between health checks
+    #[clap(long = "health-check-interval", default_value = "5")]
+    health_check_interval: u64,
+
+    /// health check timeout in milliseconds
+    #[clap(long = "health-check-timeout", default_value = "1000")]
+    health_check_timeout: u64,
+
+    /// consecutive successes before the child is ready
+    #[clap(long = "healthy-threshold", default_value = "1")]
+    healthy_threshold: u32,
+
+    /// consecutive failures before the child is marked down
+    #[clap(long = "unhealthy-threshold", default_value = "3")]
+    unhealthy_threshold: u32,
+
+    /// milliseconds a request waits for the child to be ready before a 503, 0 answers 503 right away
+    #[clap(long = "readiness-hold", default_value = "5000")]
+    readiness_hold: u64,
+
+    /// path of the gasket health probe (gasket, child process, upstream and certificates)
+    #[clap(long = "healthz-path", default_value = "/healthz")]
+    healthz_path: String,
+
+    /// path of the readiness probe (upstream ready and certificates valid)
+    #[clap(long = "readyz-path", default_value = "/readyz")]
+    readyz_path: String,
+
+    /// path of the liveness probe (gasket itself)
+    #[clap(long = "livez-path", default_value = "/livez")]
+    livez_path: String,
+
+    /// path of the Prometheus metrics
+    #[clap(long = "metrics-path", default_value = "/metrics")]
+    metrics_path: String,
+
+    /// serve the probes and metrics on this port instead of the proxy port, 0 serves them on the proxy port
+    #[clap(long = "admin-port", default_value = "0")]
+    admin_port: u16,
+
+    /// path of the rolling restart endpoint (POST), served with an admin token only
+    #[clap(long = "admin-restart-path", default_value = "/restart")]
+    admin_restart_path: String,
+
+    /// file holding the bearer token of the admin endpoints (or GASKET_ADMIN_TOKEN), none disables them
+    #[clap(long = "admin-token-file")]
+    admin_token_file: Option<String>,
+
+    /// max keys tracked by each stability pattern (throttlers, retry budgets, circuit breakers)
+    #[clap(long = "max-keys", default_value = "10000")]
+    max_keys: usize,
+
+    /// seconds before the state of an unused key is evicted
+    #[clap(long = "key-idle-timeout", default_value = "300")]
+    key_idle_timeout: u64,
+
+    /// upstream timeout in seconds for each attempt
+    #[clap(long = "upstream-timeout", default_value = "5")]
+    upstream_timeout: u64,
+
+    /// tracing: export request spans with OTLP and propagate the trace context to the child
+    #[clap(long = "tracing")]
+    tracing_enabled: bool,
+
+    /// OTLP collector endpoint (default http://127.0.0.1:4318 for http, http://127.0.0.1:4317 for grpc)
+    #[clap(long = "otlp-endpoint")]
+    otlp_endpoint: Option<String>,
+
+    /// OTLP export protocol
+    #[clap(long = "otlp-protocol", default_value = "http", possible_values = &["http", "grpc"])]
+    otlp_protocol: telemetry::OtlpProtocol,
+
+    /// trace context formats read from requests (first match wins) and sent to the child: w3c, b3, b3multi
+    #[clap(
+        long = "trace-propagation",
+        default_value = "w3c,b3",
+        use_delimiter = true
+    )]
+    trace_propagation: Vec<telemetry::Propagation>,
+
+    /// ratio of new traces sampled, requests carrying a sampling decision keep it
+    #[clap(long = "trace-sample-ratio", default_value = "1.0")]
+    trace_sample_ratio: f64,
+
+    /// service.name of the exported spans
+    #[clap(long = "service-name", default_value = "gasket")]
+    service_name: String,
+
+    /// access log written on stdout
+    #[clap(long = "access-log", default_value = "json", possible_values = &["json", "text", "off"])]
+    access_log: access_log::AccessLogFormat,
+
+    /// fields of the json access log, in order
+    #[clap(
+        long = "access-log-fields",
+        default_value = "timestamp,request_id,client_ip,identity,method,path,status,bytes_in,bytes_out,upstream_latency_ms,latency_ms,retries,circuit",
+        use_delimiter = true
+    )]
+    access_log_fields: Vec<access_log::AccessLogField>,
+
+    /// template of the text access log, {field} is replaced by the field value
+    #[clap(
+        long = "access-log-template",
+        default_value = "{timestamp} {client_ip} \"{method} {path}\" {status} {bytes_out} {latency_ms}ms {request_id}"
+    )]
+    access_log_template: String,
+
+    /// log only a ratio of the requests of a route (route=ratio), server errors are always logged, repeatable
+    #[clap(
+        long = "access-log-sample",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    access_log_samples: Vec<access_log::SampleRule>,
+
+    /// headers an incoming request id is taken from, in order
+    #[clap(
+        long = "request-id-accept",
+        default_value = "x-request-id,x-gasket-request-id",
+        use_delimiter = true
+    )]
+    request_id_accept: Vec<String>,
+
+    /// header carrying the request id to the child and back to the client
+    #[clap(long = "request-id-header", default_value = "x-gasket-request-id")]
+    request_id_header: String,
+
+    /// longest incoming request id accepted, longer ids are replaced by a new one
+    #[clap(long = "request-id-max-length", default_value = "128")]
+    request_id_max_length: usize,
+
+    /// pipe the child stdout/stderr through gasket, tagged with source, pid and stream
+    #[clap(long = "capture-output")]
+    capture_output: bool,
+
+    /// format of the captured child output
+    #[clap(long = "output-format", default_value = "text", possible_values = &["text", "json"])]
+    output_format: child_output::OutputFormat,
+
+    /// regex matching continuation lines merged with the previous line (stack traces), empty disables merging
+    #[clap(long = "output-multiline", default_value = "^[ \\t]")]
+    output_multiline: String,
+
+    /// max captured records per second and stream, excess is dropped, 0 is unlimited
+    #[clap(long = "output-rate-limit", default_value = "0")]
+    output_rate_limit: u32,
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // PORT will always be a pair like:
-    // Origin port is 3000, destination port will be 3001
-    // Gasket increments the port by one based on the PORT env var
-    // or defaults to port 3000
-    let port: u16 = env::var("PORT")
-        .map(|s| s.parse().unwrap_or(3000))
-        .unwrap_or(3000);
-    let dest_port = Arc::new(port + 1);
-
-    // proxy settings: always bind to localhost, always proxy to localhost
-    let listen_addr = format!("127.0.0.1:{}", port.to_string());
     let gasket_options = GasketOptions::parse();
 
-    std::env::set_var("RUST_LOG", "actix_web=debug,actix_server=debug,gasket=info");
-    env_logger::init();
+    // RUST_LOG set by the user wins over the default filter
+    env_logger::Builder::from_env(
+        env_logger::Env::default()
+            .default_filter_or("actix_web=debug,actix_server=debug,gasket=info"),
+    )
+    .init();
 
     info!("Gasket --");
-    let cmd = gasket_options.command.clone();
+    let ports = match ports::Ports::resolve(
+        gasket_options.listen_port,
+        gasket_options.upstream_port,
+        gasket_options.upstream_port_env.clone(),
+    ) {
+        Ok(ports) => Arc::new(ports),
+        Err(e) => {
+            info!("Ports Abort: {}", e);
+            std::process::exit(-1);
+        }
+    };
+    info!(
+        "Listening on {}, upstream on {}",
+        ports.listen,
+        ports.upstream()
+    );
+    // proxy settings: always bind to localhost, always proxy to localhost
+    let listen_addr = format!("127.0.0.1:{}", ports.listen);
+    let command = match (
+        gasket_options.command.as_ref(),
+        gasket_options.exec.is_empty(),
+    ) {
+        (Some(_), false) => {
+            info!("Command Abort: -e and -- cmd can't be used together");
+            std::process::exit(-1);
+        }
+        (Some(line), true) => Some(process_manager::CommandLine::Line(line.clone())),
+        (None, false) => Some(process_manager::CommandLine::Args(
+            gasket_options.exec.clone(),
+        )),
+        (None, true) => None,
+    };
+    let specs = match gasket_options.processes.as_ref() {
+        Some(_) if command.is_some() => {
+            info!("Processes Abort: -e/-- cmd and --processes can't be used together");
+            std::process::exit(-1);
+        }
+        Some(path) => match process_manager::ProcessSpec::load(path) {
+            Ok(specs) => specs,
+            Err(e) => {
+                info!("Processes Abort: {}", e);
+                std::process::exit(-1);
+            }
+        },
+        None => match command {
+            Some(command) => {
+                match process_manager::ProcessSpec::from_command(command, gasket_options.shell) {
+                    Ok(spec) => vec![process_manager::ProcessSpec {
+                        restart: gasket_options.restart,
+                        ..spec
+                    }],
+                    Err(e) => {
+                        info!("Command Abort: {}", e);
+                        std::process::exit(-1);
+                    }
+                }
+            }
+            None => Vec::new(),
+        },
+    };
+
+    let identity = match gasket_options.user.as_ref() {
+        Some(user) => match privileges::Identity::resolve(
+            user,
+            gasket_options.group.as_deref(),
+            &gasket_options.supplementary_groups,
+        ) {
+            Ok(identity) => Some(identity),
+            Err(e) => {
+                info!("Privileges Abort: {}", e);
+                std::process::exit(-1);
+            }
+        },
+        None if gasket_options.group.is_some() || gasket_options.drop_privileges => {
+            info!("Privileges Abort: --group and --drop-privileges need --user");
+            std::process::exit(-1);
+        }
+        None => None,
+    };
+
+    // the admin token, read before privileges are dropped
+    let admin_token = match gasket_options.admin_token_file.as_ref() {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(token) => Some(token.trim().to_string()),
+            Err(e) => {
+                info!("Admin Abort: {}: {}", path, e);
+                std::process::exit(-1);
+            }
+        },
+        None => std::env::var("GASKET_ADMIN_TOKEN").ok(),
+    };
+    if admin_token.as_ref().is_some_and(|token| token.is_empty()) {
+        info!("Admin Abort: empty admin token");
+        std::process::exit(-1);
+    }
+
+    let mut env_settings = child_env::EnvSettings {
+        allow: gasket_options.env_allow.clone(),
+        deny: gasket_options.env_deny.clone(),
+        vars: Vec::new(),
+        settings: [
+            ("GASKET_LISTEN_PORT", ports.listen.to_string()),
+            ("GASKET_UPSTREAM_PORT", ports.upstream().to_string()),
+            (
+                "GASKET_REQUEST_ID_HEADER",
+                gasket_options.request_id_header.clone(),
+            ),
+            ("GASKET_SERVICE_NAME", gasket_options.service_name.clone()),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect(),
+        secret_files: gasket_options.secret_files.clone(),
+    };
+    if let Err(e) = env_settings.load_files(&gasket_options.env_files) {
+        info!("Env Abort: {}", e);
+        std::process::exit(-1);
+    }
+    env_settings.vars.extend(gasket_options.env.iter().cloned());
+
+    let health_check = health_check::HealthCheckSettings {
+        name: "Upstream".to_string(),
+        kind: gasket_options.health_check,
+        path: gasket_options.health_check_path.clone(),
+        command: gasket_options.health_check_command.clone(),
+        interval: chrono::Duration::seconds(gasket_options.health_check_interval as i64),
+        timeout: chrono::Duration::milliseconds(gasket_options.health_check_timeout as i64),
+        healthy_threshold: gasket_options.healthy_threshold,
+        unhealthy_threshold: gasket_options.unhealthy_threshold,
+        hold: chrono::Duration::milliseconds(gasket_options.readiness_hold as i64),
+    };
+    let upstream = ports.clone();
+    let readiness =
+        health_check::HealthChecker::start(health_check.clone(), move || upstream.upstream());
 
     info!("Starting process manager");
-    let handle = process_manager::StaticProcessManager::run(cmd).await;
+    let output = if gasket_options.capture_output {
+        let multiline = if gasket_options.output_multiline.is_empty() {
+            None
+        } else {
+            match regex::Regex::new(&gasket_options.output_multiline) {
+                Ok(re) => Some(re),
+                Err(e) => {
+                    info!("Output Abort: {}", e);
+                    std::process::exit(-1);
+                }
+            }
+        };
+        Some(child_output::OutputSettings {
+            format: gasket_options.output_format,
+            multiline,
+            rate_limit: gasket_options.output_rate_limit,
+        })
+    } else {
+        None
+    };
+    let restart = process_manager::RestartSettings {
+        backoff_initial: chrono::Duration::milliseconds(
+            gasket_options.restart_backoff_initial as i64,
+        ),
+        backoff_max: chrono::Duration::milliseconds(gasket_options.restart_backoff_max as i64),
+        limit: gasket_options.restart_limit,
+        window: chrono::Duration::seconds(gasket_options.restart_window as i64),
+        exceeded: gasket_options.restart_exceeded,
+        rolling_timeout: chrono::Duration::seconds(gasket_options.rolling_restart_timeout as i64),
+    };
+    let mut limits = limits::Limits {
+        rlimits: [
+            (libc::RLIMIT_NOFILE, gasket_options.rlimit_nofile),
+            (libc::RLIMIT_CORE, gasket_options.rlimit_core),
+            (libc::RLIMIT_NPROC, gasket_options.rlimit_nproc),
+            (libc::RLIMIT_AS, gasket_options.rlimit_as),
+        ]
+        .iter()
+        .filter_map(|(resource, limit)| limit.map(|limit| (*resource as libc::c_int, limit)))
+        .collect(),
+        cgroup: limits::CgroupLimits {
+            memory_max: gasket_options.cgroup_memory_max,
+            cpu_max: gasket_options.cgroup_cpu_max,
+            pids_max: gasket_options.cgroup_pids_max,
+        },
+        ..Default::default()
+    };
+    // cgroups and rlimits are set up while gasket is still privileged
+    let names: Vec<String> = specs.iter().map(|spec| spec.name.clone()).collect();
+    let owner = match identity.as_ref() {
+        Some(identity) if gasket_options.drop_privileges => Some((identity.uid, identity.gid)),
+        _ => None,
+    };
+    limits.prepare(&names, owner);
+    let reaper = reaper::start();
+    let shutdown = Arc::new(shutdown::Shutdown::new());
+    let processes = process_manager::ProcessSupervisor::run(
+        specs,
+        readiness.clone(),
+        &health_check,
+        restart,
+        output,
+        signals::SignalSettings {
+            rewrites: gasket_options.signal_rewrites.clone(),
+            target: gasket_options.signal_target,
+            sighup: gasket_options.sighup,
+            stop_signal: gasket_options.stop_signal,
+            stop_grace_period: chrono::Duration::seconds(gasket_options.stop_grace_period as i64),
+        },
+        privileges::Privileges {
+            identity,
+            no_new_privs: gasket_options.no_new_privs,
+        },
+        env_settings,
+        ports.clone(),
+        limits,
+        shutdown.clone(),
+    )
+    .await;
+
+    let health = Arc::new(admin::HealthState::new(
+        readiness,
+        processes.states.clone(),
+        shutdown.clone(),
+        admin::HealthPaths {
+            healthz: gasket_options.healthz_path.clone(),
+            readyz: gasket_options.readyz_path.clone(),
+            livez: gasket_options.livez_path.clone(),
+            metrics: gasket_options.metrics_path.clone(),
+            restart: gasket_options.admin_restart_path.clone(),
+        },
+        admin_token.map(|token| admin::AdminRestart {
+            token,
+            restarter: processes.restarter.clone(),
+        }),
+    ));
+    let metrics = Arc::new(metrics::Metrics::new(
+        health.clone(),
+        gasket_options.max_keys,
+    ));
+    let tracer = if gasket_options.tracing_enabled {
+        let endpoint = match (&gasket_options.otlp_endpoint, gasket_options.otlp_protocol) {
+            (Some(endpoint), _) => endpoint.clone(),
+            (None, telemetry::OtlpProtocol::Http) => "http://127.0.0.1:4318".to_string(),
+            (None, telemetry::OtlpProtocol::Grpc) => "http://127.0.0.1:4317".to_string(),
+        };
+        Some(telemetry::Tracer::start(telemetry::TracingSettings {
+            endpoint,
+            protocol: gasket_options.otlp_protocol,
+            propagation: gasket_options.trace_propagation.clone(),
+            sample_ratio: gasket_options.trace_sample_ratio,
+            service_name: gasket_options.service_name.clone(),
+        }))
+    } else {
+        None
+    };
+    if gasket_options.admin_port != 0 {
+        let admin_addr = format!("127.0.0.1:{}", gasket_options.admin_port);
+        match server::admin_server(health.clone(), metrics.clone(), admin_addr) {
+            Ok(admin) => {
+                actix_web::rt::spawn(async move {
+                    if let Err(e) = admin.await {
+                        info!("Admin server error: {}", e);
+                    }
+                });
+            }
+            Err(e) => info!("Admin server error: {}", e),
+        }
+    }
+    let stop_signal = gasket_options.stop_signal;
+    let stop_grace_period = gasket_options.stop_grace_period;
+    let kill_cgroup = gasket_options.kill_cgroup;
     // mTLS supercedes tls (if mtls is enable -t/--tls is ignored)
     // defaults to http server if none is set
-    if gasket_options.mtls_enabled {
-        let s = server::mtls_server(gasket_options, dest_port, listen_addr).await;
-        handle.close();
-        return s;
+    let s = if gasket_options.mtls_enabled {
+        server::mtls_server(
+            gasket_options,
+            ports.clone(),
+            listen_addr,
+            health,
+            metrics,
+            tracer,
+            shutdown.clone(),
+        )
+        .await
     } else if gasket_options.tls_enabled {
-        let s = server::tls_server(gasket_options, dest_port, listen_addr).await;
-        handle.close();
-        return s;
-    }
-    let s = server::http_server(gasket_options, dest_port, listen_addr).await;
-    handle.close();
-    return s;
+        server::tls_server(
+            gasket_options,
+            ports.clone(),
+            listen_addr,
+            health,
+            metrics,
+            tracer,
+            shutdown.clone(),
+        )
+        .await
+    } else {
+        server::http_server(
+            gasket_options,
+            ports.clone(),
+            listen_addr,
+            health,
+            metrics,
+            tracer,
+            shutdown.clone(),
+        )
+        .await
+    };
+
+    // the servers are stopped, then the processes
+    processes
+        .stop(
+            stop_signal,
+            chrono::Duration::seconds(stop_grace_period as i64),
+            kill_cgroup,
+        )
+        .await;
+    processes.close();
+    reaper.close();
+    s?;
+    let exit_code = shutdown
+        .exit_code()
+        .unwrap_or_else(|| processes.exit_code());
+    info!("Gasket exiting with code {}", exit_code);
+    std::process::exit(exit_code);
 }
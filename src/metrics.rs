@@ -0,0 +1,691 @@
+use crate::admin::HealthState;
+use crate::process_manager::ProcessState;
+use crate::routes::{RouteKey, RouteTable};
+use crate::stability_patterns::{CircuitState, StabilityPatterns};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::Method;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use chrono::Local;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+// Prometheus metrics, rendered in the text exposition format on the metrics path
+// Request metrics are labeled by route key (see routes.rs), distinct route labels are capped
+// so raw paths can't blow up the series count, extra routes are reported as OTHER_ROUTE.
+// Without --route keys are raw paths, they are all labeled with the other key (--route-other).
+// Circuit breakers sharing a label are reported with the worst state. Extension methods are
+// labeled OTHER_METHOD, clients pick them freely.
+const OTHER_ROUTE: &str = "other";
+const OTHER_METHOD: &str = "OTHER";
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// counters and gauges sharing a name, one value per label set
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, f64>>,
+}
+
+#[derive(Clone, Default)]
+struct HistogramData {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, HistogramData>>,
+}
+
+pub struct Metrics {
+    health: Arc<HealthState>,
+    stability_patterns: Mutex<Option<Arc<StabilityPatterns>>>,
+    route_table: Mutex<Option<Arc<RouteTable>>>,
+    routes: Mutex<HashSet<String>>,
+    max_routes: usize,
+    requests: Family,
+    latency: Histogram,
+    in_flight: Family,
+    upstream_errors: Family,
+    rejected: Family,
+    retries: Family,
+    retries_exhausted: Family,
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn label_set(names: &[&str], values: &[String]) -> String {
+    if names.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = names
+        .iter()
+        .zip(values.iter())
+        .map(|(n, v)| format!("{}=\"{}\"", n, escape(v)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn method_label(method: &Method) -> &str {
+    match *method {
+        Method::GET
+        | Method::HEAD
+        | Method::POST
+        | Method::PUT
+        | Method::DELETE
+        | Method::CONNECT
+        | Method::OPTIONS
+        | Method::TRACE
+        | Method::PATCH => method.as_str(),
+        _ => OTHER_METHOD,
+    }
+}
+
+// the worst state of the circuit breakers sharing a label: open, then half open, then closed
+fn worst_states(
+    states: Vec<(String, CircuitState)>,
+    label: impl Fn(&str) -> String,
+) -> BTreeMap<String, CircuitState> {
+    let severity = |state: CircuitState| match state {
+        CircuitState::Open => 2,
+        CircuitState::HalfOpen => 1,
+        CircuitState::Closed => 0,
+    };
+    let mut worst: BTreeMap<String, CircuitState> = BTreeMap::new();
+    for (route, state) in states {
+        let current = worst.entry(label(&route)).or_insert(CircuitState::Closed);
+        if severity(state) > severity(*current) {
+            *current = state;
+        }
+    }
+    worst
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl Family {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        kind: &'static str,
+        labels: &'static [&'static str],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            kind,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn add(&self, labels: &[&str], v: f64) {
+        let key = labels.iter().map(|l| l.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_insert(0.0) += v;
+    }
+
+    fn inc(&self, labels: &[&str]) {
+        self.add(labels, 1.0);
+    }
+
+    fn total(&self) -> f64 {
+        self.values.lock().unwrap().values().sum()
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, self.kind);
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                self.name,
+                label_set(self.labels, labels),
+                value
+            );
+        }
+    }
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn observe(&self, labels: &[&str], v: f64) {
+        let key = labels.iter().map(|l| l.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        let data = values.entry(key).or_insert_with(|| HistogramData {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            ..Default::default()
+        });
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if v <= *bound {
+                data.buckets[i] += 1;
+            }
+        }
+        data.sum += v;
+        data.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        let mut names = self.labels.to_vec();
+        names.push("le");
+        for (labels, data) in self.values.lock().unwrap().iter() {
+            let mut values = labels.clone();
+            values.push(String::new());
+            let last = values.len() - 1;
+            for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+                values[last] = bound.to_string();
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    self.name,
+                    label_set(&names, &values),
+                    data.buckets[i]
+                );
+            }
+            values[last] = "+Inf".to_string();
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                self.name,
+                label_set(&names, &values),
+                data.count
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{} {}",
+                self.name,
+                label_set(self.labels, labels),
+                data.sum
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{} {}",
+                self.name,
+                label_set(self.labels, labels),
+                data.count
+            );
+        }
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// one sample per supervised process, nothing when no process is supervised
+fn per_process(out: &mut String, name: &str, help: &str, kind: &str, values: Vec<(&str, f64)>) {
+    if values.is_empty() {
+        return;
+    }
+    header(out, name, help, kind);
+    for (process, value) in values {
+        let _ = writeln!(
+            out,
+            "{}{} {}",
+            name,
+            label_set(&["process"], &[process.to_string()]),
+            value
+        );
+    }
+}
+
+// resident memory and open file descriptors of a process, from /proc
+fn process_usage(pid: &str) -> Option<(f64, f64)> {
+    let statm = std::fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    let pages: f64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as f64;
+    let fds = std::fs::read_dir(format!("/proc/{}/fd", pid)).ok()?.count() as f64;
+    Some((pages * page_size, fds))
+}
+
+impl Metrics {
+    pub fn new(health: Arc<HealthState>, max_routes: usize) -> Self {
+        Self {
+            health,
+            stability_patterns: Mutex::new(None),
+            route_table: Mutex::new(None),
+            routes: Mutex::new(HashSet::new()),
+            max_routes,
+            requests: Family::new(
+                "gasket_requests_total",
+                "Requests handled by gasket.",
+                "counter",
+                &["route", "method", "status"],
+            ),
+            latency: Histogram::new(
+                "gasket_request_duration_seconds",
+                "Time to answer a request, including upstream attempts.",
+                &["route", "method", "status"],
+            ),
+            in_flight: Family::new(
+                "gasket_requests_in_flight",
+                "Requests being handled.",
+                "gauge",
+                &["route", "method"],
+            ),
+            upstream_errors: Family::new(
+                "gasket_upstream_errors_total",
+                "Upstream attempts that failed before a response, by error type.",
+                "counter",
+                &["route", "type"],
+            ),
+            rejected: Family::new(
+                "gasket_rejected_requests_total",
+                "Requests answered by gasket without reaching the upstream, by reason.",
+                "counter",
+                &["route", "reason"],
+            ),
+            retries: Family::new(
+                "gasket_upstream_retries_total",
+                "Upstream attempts retried with backoff.",
+                "counter",
+                &["route"],
+            ),
+            retries_exhausted: Family::new(
+                "gasket_retries_exhausted_total",
+                "Retries given up on, by reason (deadline, budget).",
+                "counter",
+                &["route", "reason"],
+            ),
+        }
+    }
+
+    // stability patterns report the circuit breaker states and the concurrency limit
+    pub fn watch_stability_patterns(&self, sp: Arc<StabilityPatterns>) {
+        *self.stability_patterns.lock().unwrap() = Some(sp);
+    }
+
+    // route keys are mapped to their label with the route table of the server
+    pub fn watch_routes(&self, routes: Arc<RouteTable>) {
+        *self.route_table.lock().unwrap() = Some(routes);
+    }
+
+    fn route_label(&self, route: &str) -> String {
+        let route = match self.route_table.lock().unwrap().as_ref() {
+            Some(table) => table.label(route).to_string(),
+            None => route.to_string(),
+        };
+        let route = route.as_str();
+        let mut routes = self.routes.lock().unwrap();
+        if routes.contains(route) {
+            return route.to_string();
+        }
+        if routes.len() >= self.max_routes {
+            return OTHER_ROUTE.to_string();
+        }
+        routes.insert(route.to_string());
+        route.to_string()
+    }
+
+    pub fn upstream_error(&self, route: &str, kind: &str) {
+        let route = self.route_label(route);
+        self.upstream_errors.inc(&[&route, kind]);
+    }
+
+    // requests being handled, waited for when gasket shuts down
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.total() as u64
+    }
+
+    // reason: throttled, circuit_open, concurrency, not_ready
+    pub fn rejected(&self, route: &str, reason: &str) {
+        let route = self.route_label(route);
+        self.rejected.inc(&[&route, reason]);
+    }
+
+    pub fn retry(&self, route: &str) {
+        let route = self.route_label(route);
+        self.retries.inc(&[&route]);
+    }
+
+    pub fn retries_exhausted(&self, route: &str, reason: &str) {
+        let route = self.route_label(route);
+        self.retries_exhausted.inc(&[&route, reason]);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.requests.render(&mut out);
+        self.latency.render(&mut out);
+        self.in_flight.render(&mut out);
+        self.upstream_errors.render(&mut out);
+        self.rejected.render(&mut out);
+        self.retries.render(&mut out);
+        self.retries_exhausted.render(&mut out);
+
+        if let Some(sp) = self.stability_patterns.lock().unwrap().as_ref() {
+            header(
+                &mut out,
+                "gasket_circuit_breaker_state",
+                "Circuit breaker state by route (0 closed, 1 open, 2 half open).",
+                "gauge",
+            );
+            let states = worst_states(sp.circuit_states(), |route| self.route_label(route));
+            for (route, state) in states {
+                let _ = writeln!(
+                    out,
+                    "gasket_circuit_breaker_state{} {}",
+                    label_set(&["route"], &[route]),
+                    state as u8
+                );
+            }
+            if let Some(limiter) = sp.concurrency_limiter.as_ref() {
+                gauge(
+                    &mut out,
+                    "gasket_concurrency_limit",
+                    "Current upstream concurrency limit.",
+                    limiter.limit() as f64,
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "gasket_tls_handshake_failures_total",
+            "TLS handshakes aborted with a fatal alert, by alert and the side sending it.",
+            "counter",
+        );
+        for ((side, reason), count) in crate::tls_utils::handshake_failures() {
+            let _ = writeln!(
+                out,
+                "gasket_tls_handshake_failures_total{} {}",
+                label_set(&["side", "reason"], &[side, reason]),
+                count
+            );
+        }
+
+        gauge(
+            &mut out,
+            "gasket_upstream_ready",
+            "Whether the upstream passes its health checks.",
+            self.health.readiness.is_ready() as u8 as f64,
+        );
+
+        let processes: Vec<ProcessState> = self
+            .health
+            .processes
+            .iter()
+            .map(|p| p.lock().unwrap().clone())
+            .collect();
+        per_process(
+            &mut out,
+            "gasket_child_up",
+            "Whether the supervised process is running.",
+            "gauge",
+            processes
+                .iter()
+                .map(|p| (p.name.as_str(), p.running as u8 as f64))
+                .collect(),
+        );
+        per_process(
+            &mut out,
+            "gasket_child_restarts_total",
+            "Restarts of the supervised process.",
+            "counter",
+            processes
+                .iter()
+                .map(|p| (p.name.as_str(), p.restarts as f64))
+                .collect(),
+        );
+        per_process(
+            &mut out,
+            "gasket_child_oom_kills_total",
+            "Times the supervised process was killed by the OOM killer.",
+            "counter",
+            processes
+                .iter()
+                .map(|p| (p.name.as_str(), p.oom_kills as f64))
+                .collect(),
+        );
+        per_process(
+            &mut out,
+            "gasket_child_uptime_seconds",
+            "Seconds since the supervised process was started.",
+            "gauge",
+            processes
+                .iter()
+                .filter(|p| p.running)
+                .filter_map(|p| p.started_at.map(|s| (p.name.as_str(), s)))
+                .map(|(name, s)| (name, (Local::now() - s).num_milliseconds() as f64 / 1000.0))
+                .collect(),
+        );
+        let usage: Vec<(&str, (f64, f64))> = processes
+            .iter()
+            .filter_map(|p| {
+                p.pid
+                    .and_then(|pid| process_usage(&pid.to_string()))
+                    .map(|u| (p.name.as_str(), u))
+            })
+            .collect();
+        per_process(
+            &mut out,
+            "gasket_child_resident_memory_bytes",
+            "Resident memory of the supervised process.",
+            "gauge",
+            usage.iter().map(|(name, (rss, _))| (*name, *rss)).collect(),
+        );
+        per_process(
+            &mut out,
+            "gasket_child_open_fds",
+            "Open file descriptors of the supervised process.",
+            "gauge",
+            usage.iter().map(|(name, (_, fds))| (*name, *fds)).collect(),
+        );
+
+        gauge(
+            &mut out,
+            "process_start_time_seconds",
+            "Start time of gasket since unix epoch in seconds.",
+            self.health.started_at().timestamp() as f64,
+        );
+        if let Some((rss, fds)) = process_usage("self") {
+            gauge(
+                &mut out,
+                "process_resident_memory_bytes",
+                "Resident memory of gasket.",
+                rss,
+            );
+            gauge(
+                &mut out,
+                "process_open_fds",
+                "Open file descriptors of gasket.",
+                fds,
+            );
+        }
+        out
+    }
+}
+
+pub async fn metrics(metrics: web::Data<Arc<Metrics>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+// Middleware recording request counts, latency and in-flight requests, it also resolves the
+// route key once and stores it in the request extensions for the proxy
+pub struct RequestMetrics {
+    metrics: Arc<Metrics>,
+    routes: Arc<RouteTable>,
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+    metrics: Arc<Metrics>,
+    routes: Arc<RouteTable>,
+}
+
+// an in-flight request, counted down when the request completes or its future is dropped
+// (client gone before the response)
+struct InFlight {
+    metrics: Arc<Metrics>,
+    route: String,
+    method: String,
+}
+
+impl InFlight {
+    fn start(metrics: Arc<Metrics>, route: String, method: String) -> Self {
+        metrics.in_flight.add(&[&route, &method], 1.0);
+        InFlight {
+            metrics,
+            route,
+            method,
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.metrics
+            .in_flight
+            .add(&[&self.route, &self.method], -1.0);
+    }
+}
+
+impl RequestMetrics {
+    pub fn new(metrics: Arc<Metrics>, routes: Arc<RouteTable>) -> Self {
+        Self { metrics, routes }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware {
+            service,
+            metrics: self.metrics.clone(),
+            routes: self.routes.clone(),
+        })
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let route_key = self.routes.key(req.path());
+        req.extensions_mut().insert(RouteKey(route_key.clone()));
+
+        let metrics = self.metrics.clone();
+        let route = metrics.route_label(&route_key);
+        let method = method_label(req.method()).to_string();
+        let started = Local::now();
+        let in_flight = InFlight::start(metrics.clone(), route.clone(), method.clone());
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            drop(in_flight);
+            let status = match &res {
+                Ok(res) => res.status().as_u16().to_string(),
+                Err(e) => e.as_response_error().status_code().as_u16().to_string(),
+            };
+            let elapsed = (Local::now() - started).num_microseconds().unwrap_or(0) as f64 / 1e6;
+            metrics.requests.inc(&[&route, &method, &status]);
+            metrics
+                .latency
+                .observe(&[&route, &method, &status], elapsed);
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn method_labels() {
+        assert_eq!(method_label(&Method::GET), "GET");
+        assert_eq!(method_label(&Method::PATCH), "PATCH");
+        assert_eq!(
+            method_label(&Method::from_bytes(b"PROPFIND").unwrap()),
+            "OTHER"
+        );
+        assert_eq!(
+            method_label(&Method::from_bytes(b"X-RANDOM-42").unwrap()),
+            "OTHER"
+        );
+    }
+
+    #[test]
+    fn worst_circuit_states() {
+        let states = |routes: &RouteTable, states: &[(&str, CircuitState)]| {
+            let states = states
+                .iter()
+                .map(|(key, state)| (routes.key(key), *state))
+                .collect();
+            worst_states(states, |key| routes.label(key).to_string())
+        };
+        // raw paths all share the other label
+        let raw = RouteTable::new(&[], "other".to_string()).unwrap();
+        let merged = states(
+            &raw,
+            &[
+                ("/a", CircuitState::Closed),
+                ("/b", CircuitState::HalfOpen),
+                ("/c", CircuitState::Open),
+                ("/d", CircuitState::HalfOpen),
+            ],
+        );
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged["other"], CircuitState::Open);
+
+        let routes = RouteTable::new(&["/users/{id}".to_string()], "other".to_string()).unwrap();
+        let merged = states(
+            &routes,
+            &[
+                ("/users/1", CircuitState::HalfOpen),
+                ("/users/2", CircuitState::Closed),
+                ("/orders/1", CircuitState::Closed),
+            ],
+        );
+        assert_eq!(merged["/users/{id}"], CircuitState::HalfOpen);
+        assert_eq!(merged["other"], CircuitState::Closed);
+    }
+}
@@ -0,0 +1,79 @@
+use log::info;
+use std::env;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+// Ports, the single source of truth shared by the servers and the process manager: gasket
+// listens on --listen-port (PORT, 3000 by default) and proxies to --upstream-port (the listen
+// port + 1 by default). The proxied process gets the upstream port in the --upstream-port-env
+// variables (PORT by default). 0 picks a free port, so does an upstream port that would be
+// past 65535.
+#[derive(Debug)]
+pub struct Ports {
+    pub listen: u16,
+    upstream: AtomicU16,
+    pub env: Vec<String>,
+}
+
+// a port nothing listens on right now
+pub fn free() -> Result<u16, String> {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .map_err(|e| format!("no free port: {}", e))
+}
+
+impl Ports {
+    pub fn resolve(
+        listen: Option<u16>,
+        upstream: Option<u16>,
+        env_names: Vec<String>,
+    ) -> Result<Self, String> {
+        let listen = match listen {
+            Some(port) => port,
+            None => match env::var("PORT") {
+                Ok(port) => port
+                    .parse()
+                    .map_err(|_| format!("PORT: invalid port {}", port))?,
+                Err(_) => 3000,
+            },
+        };
+        let listen = match listen {
+            0 => free()?,
+            port => port,
+        };
+        let upstream = match upstream {
+            Some(0) => free()?,
+            Some(port) => port,
+            None => match listen.checked_add(1) {
+                Some(port) => port,
+                None => {
+                    let port = free()?;
+                    info!("No port after {}, upstream on free port {}", listen, port);
+                    port
+                }
+            },
+        };
+        if upstream == listen {
+            return Err(format!("listen and upstream ports are both {}", listen));
+        }
+        if env_names.iter().any(|name| name.is_empty()) {
+            return Err("empty upstream port variable name".to_string());
+        }
+        Ok(Ports {
+            listen,
+            upstream: AtomicU16::new(upstream),
+            env: env_names,
+        })
+    }
+
+    // port of the proxied process, requests are forwarded to it
+    pub fn upstream(&self) -> u16 {
+        self.upstream.load(Ordering::Relaxed)
+    }
+
+    // switches requests to another port, after a rolling restart
+    pub fn set_upstream(&self, port: u16) {
+        self.upstream.store(port, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,184 @@
+use log::info;
+use std::ffi::{CStr, CString};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+// Users and groups: gasket often starts as root to read its keys and bind, the processes run as
+// --user/--group with their supplementary groups (the groups of the user by default) and
+// gasket itself can become that same user once the TLS material is loaded and the listener is
+// bound, it can still signal the processes then. no_new_privs keeps setuid binaries and file
+// capabilities from giving the processes more privileges than they start with.
+
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub groups: Vec<libc::gid_t>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Privileges {
+    pub identity: Option<Identity>,
+    pub no_new_privs: bool,
+}
+
+// a passwd entry: uid, primary gid and name
+fn passwd(user: &str) -> Option<(libc::uid_t, libc::gid_t, String)> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let rc = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+        Err(_) => {
+            let name = CString::new(user).ok()?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            }
+        }
+    };
+    if rc != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(pwd.pw_name) }
+        .to_string_lossy()
+        .into_owned();
+    Some((pwd.pw_uid, pwd.pw_gid, name))
+}
+
+// a group by name or number
+fn group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| format!("unknown group: {}", group))?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let rc = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 || result.is_null() {
+        return Err(format!("unknown group: {}", group));
+    }
+    Ok(grp.gr_gid)
+}
+
+// the groups a user belongs to, from /etc/group
+fn user_groups(name: &str, gid: libc::gid_t) -> Vec<libc::gid_t> {
+    let name = match CString::new(name) {
+        Ok(name) => name,
+        Err(_) => return vec![gid],
+    };
+    let mut count: libc::c_int = 64;
+    loop {
+        let mut groups = vec![0 as libc::gid_t; count as usize];
+        let previous = count;
+        let rc = unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if rc >= 0 {
+            groups.truncate(count as usize);
+            return groups;
+        }
+        if count <= previous {
+            count = previous * 2;
+        }
+    }
+}
+
+impl Identity {
+    // a user by name or number (a number without passwd entry is used as is), its primary group
+    // unless group is given (the uid for an unknown user), the supplementary groups of the
+    // user unless groups are given
+    pub fn resolve(
+        user: &str,
+        group_name: Option<&str>,
+        groups: &[String],
+    ) -> Result<Self, String> {
+        let (uid, primary, name) = match passwd(user) {
+            Some((uid, gid, name)) => (uid, gid, Some(name)),
+            None => match user.parse() {
+                Ok(uid) => (uid, uid, None),
+                Err(_) => return Err(format!("unknown user: {}", user)),
+            },
+        };
+        let gid = match group_name {
+            Some(g) => group(g)?,
+            None => primary,
+        };
+        let groups = if !groups.is_empty() {
+            groups
+                .iter()
+                .map(|g| group(g))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            match name {
+                Some(name) => user_groups(&name, gid),
+                None => vec![gid],
+            }
+        };
+        Ok(Identity { uid, gid, groups })
+    }
+
+    // becomes this identity, groups can only be changed by root, the uid last
+    fn apply(&self) -> std::io::Result<()> {
+        unsafe {
+            if libc::geteuid() == 0 && libc::setgroups(self.groups.len(), self.groups.as_ptr()) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            if libc::getegid() != self.gid && libc::setgid(self.gid) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if libc::geteuid() != self.uid && libc::setuid(self.uid) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    // drops gasket's own privileges
+    pub fn drop_privileges(&self) -> Result<(), String> {
+        self.apply()
+            .map_err(|e| format!("can't become uid {} gid {}: {}", self.uid, self.gid, e))?;
+        info!(
+            "Dropped privileges to uid {} gid {} groups {:?}",
+            self.uid, self.gid, self.groups
+        );
+        Ok(())
+    }
+}
+
+impl Privileges {
+    // the process is started as the identity, with no_new_privs
+    pub fn configure(&self, command: &mut Command) {
+        let identity = self.identity.clone();
+        let no_new_privs = self.no_new_privs;
+        if identity.is_none() && !no_new_privs {
+            return;
+        }
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(identity) = identity.as_ref() {
+                    identity.apply()?;
+                }
+                if no_new_privs && libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
@@ -1,164 +1,1159 @@
 use log::info;
-use std::convert::TryInto;
-use std::env;
-use std::process::Command;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::thread;
 use std::time;
 
+use crate::child_env::EnvSettings;
+use crate::child_output::{capture, OutputSettings, Stream};
+use crate::health_check::{HealthCheckKind, HealthCheckSettings, HealthChecker, Readiness};
+use crate::limits::{self, Limits, ProcessCgroup};
+use crate::ports::Ports;
+use crate::privileges::Privileges;
+use crate::process_tree;
+use crate::reaper;
+use crate::rolling_restart::{self, Restarter};
+use crate::shutdown::Shutdown;
+use crate::signals::{SighupAction, Signal, SignalSettings, FORWARDED};
+use futures::future::Either;
 use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
 use signal_hook::consts::signal::*;
 use signal_hook_tokio::Signals;
+use tokio::sync::{mpsc, oneshot};
 
+use std::os::unix::process::ExitStatusExt;
 use std::sync::Mutex;
 
+// Supervised processes: the -e command, or a list of processes loaded from a JSON file
+// {"processes": [
+//   {"name": "app", "command": "node server.js", "proxy": true, "depends_on": ["cache"]},
+//   {"name": "cache", "command": ["redis-server", "--port", "6380"], "restart": "on-failure",
+//    "env": {"K": "V"}, "cwd": "/data", "health_check": {"kind": "tcp", "port": 6380}}
+// ]}
+// - proxy: the process requests are forwarded to, it gets PORT and is checked by --health-check
+// - depends_on: the process is started once these processes are healthy (their health check
+//   passes, or they are running when they have none)
+// - restart: always (default), on-failure or never
+// - command: a string split with shell quoting ("node 'my server.js'") or an array, with
+//   "shell": true it is run by /bin/sh -c (the array items after the first are $0, $1...)
+// Restarts are delayed with an exponential backoff (doubling up to a cap, back to the initial
+// delay once a process ran longer than the cap) and limited by a budget of restarts over a
+// sliding window, once exceeded gasket either exits with the exit code of the process or stays
+// up without it (the proxied process down means 503s).
+// Once gasket shuts down processes are not restarted anymore, the exit code of the proxied
+// process (128 + signal when killed) becomes the exit code of gasket. The proxied process
+// completing (not restarted by its policy) shuts gasket down with its exit code.
+// The proxied process can also be replaced by a rolling restart (see rolling_restart), handled
+// by its manager while it waits for the process to exit.
+
+// State of a supervised process as reported by the health endpoints
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ProcessState {
+    pub name: String,
+    pub proxied: bool,
+    pub managed: bool,
+    pub running: bool,
+    // exited and not restarted by its restart policy
+    pub completed: bool,
+    pub pid: Option<u32>,
+    pub started_at: Option<chrono::DateTime<chrono::Local>>,
+    pub restarts: u32,
+    pub last_exit_code: Option<i32>,
+    pub last_exit_signal: Option<i32>,
+    // exit, signal or oom_kill
+    pub last_exit_reason: Option<String>,
+    pub oom_kills: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Always,
+    OnFailure,
+    Never,
+}
+
+// what happens once a process exceeds its restart budget
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestartExceeded {
+    Exit,
+    StayUp,
+}
+
+#[derive(Clone, Debug)]
+pub struct RestartSettings {
+    pub backoff_initial: chrono::Duration,
+    pub backoff_max: chrono::Duration,
+    // restarts allowed over the window, 0 is unlimited
+    pub limit: u32,
+    pub window: chrono::Duration,
+    pub exceeded: RestartExceeded,
+    // a rolling restart fails once the new process isn't healthy after it
+    pub rolling_timeout: chrono::Duration,
+}
+
+// restarts of a process over the window, and the delay before the next one
+struct RestartHistory {
+    restarts: VecDeque<time::Instant>,
+    backoff: chrono::Duration,
+}
+
+// a command line split with shell quoting, or the program followed by its arguments
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum CommandLine {
+    Line(String),
+    Args(Vec<String>),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProcessHealthCheck {
+    pub kind: HealthCheckKind,
+    #[serde(default)]
+    pub port: u16,
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    #[serde(default)]
+    pub command: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProcessSpec {
+    pub name: String,
+    pub command: CommandLine,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub cwd: Option<String>,
+    #[serde(default = "default_restart")]
+    pub restart: RestartPolicy,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    #[serde(default)]
+    pub health_check: Option<ProcessHealthCheck>,
+    #[serde(default)]
+    pub proxy: bool,
+    #[serde(default)]
+    pub shell: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProcessConfig {
+    processes: Vec<ProcessSpec>,
+}
+
+// Runs and restarts one process
 #[derive(Clone)]
 pub struct StaticProcessManager {
-    pid_sender: Arc<Mutex<tokio::sync::mpsc::Sender<u32>>>,
-    pid_receiver: Arc<Mutex<tokio::sync::mpsc::Receiver<u32>>>,
-    pub port: u32,
-    pub max_spawns: u32,
-    pub self_pid: u32,
-    pub cmd: String,
+    pub spec: ProcessSpec,
+    pub ports: Arc<Ports>,
+    pub restart: RestartSettings,
+    pub state: Arc<Mutex<ProcessState>>,
+    pub readiness: Arc<Readiness>,
+    // the process has no health check: it is ready while it runs
+    track_readiness: bool,
+    // None: the child inherits gasket's stdout and stderr
+    pub output: Option<OutputSettings>,
+    pub signals: SignalSettings,
+    pub privileges: Privileges,
+    pub env: EnvSettings,
+    pub limits: Limits,
+    pub shutdown: Arc<Shutdown>,
+    // rolling restarts of the proxied process
+    requests: Option<Arc<Mutex<mpsc::UnboundedReceiver<rolling_restart::Request>>>>,
+}
+
+// Starts the processes in dependency order, forwards signals to them and stops them
+pub struct ProcessSupervisor {
+    pub states: Vec<Arc<Mutex<ProcessState>>>,
+    pub restarter: Restarter,
+    signals: SignalSettings,
+    handle: signal_hook_tokio::Handle,
+}
+
+fn default_restart() -> RestartPolicy {
+    RestartPolicy::Always
+}
+
+fn default_health_check_path() -> String {
+    "/".to_string()
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(RestartPolicy::Always),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "never" => Ok(RestartPolicy::Never),
+            _ => Err(format!("unknown restart policy: {}", s)),
+        }
+    }
+}
+
+impl FromStr for RestartExceeded {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exit" => Ok(RestartExceeded::Exit),
+            "stay-up" => Ok(RestartExceeded::StayUp),
+            _ => Err(format!("unknown restart exceeded action: {}", s)),
+        }
+    }
+}
+
+impl RestartHistory {
+    fn new(settings: &RestartSettings) -> Self {
+        RestartHistory {
+            restarts: VecDeque::new(),
+            backoff: settings.backoff_initial,
+        }
+    }
+
+    // the delay before restarting a process that ran for uptime, None once the restart budget
+    // is exceeded. A process that stayed up longer than the backoff cap starts over.
+    fn next(
+        &mut self,
+        settings: &RestartSettings,
+        now: time::Instant,
+        uptime: chrono::Duration,
+    ) -> Option<chrono::Duration> {
+        let window = settings.window.to_std().unwrap_or_default();
+        while self
+            .restarts
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > window)
+        {
+            self.restarts.pop_front();
+        }
+        if settings.limit > 0 && self.restarts.len() as u32 >= settings.limit {
+            return None;
+        }
+        self.restarts.push_back(now);
+        if uptime > settings.backoff_max {
+            self.backoff = settings.backoff_initial;
+        }
+        let delay = self.backoff;
+        self.backoff = std::cmp::min(self.backoff * 2, settings.backoff_max);
+        Some(delay)
+    }
+}
+
+impl CommandLine {
+    // the program and its arguments, run by /bin/sh -c with shell
+    pub fn args(&self, shell: bool) -> Result<Vec<String>, String> {
+        let args = match (self, shell) {
+            (CommandLine::Line(line), false) => crate::shell_words::split(line)?,
+            (CommandLine::Args(args), false) => args.clone(),
+            (CommandLine::Line(line), true) if !line.trim().is_empty() => {
+                vec!["/bin/sh".to_string(), "-c".to_string(), line.clone()]
+            }
+            (CommandLine::Args(args), true)
+                if args.first().is_some_and(|a| !a.trim().is_empty()) =>
+            {
+                ["/bin/sh", "-c"]
+                    .iter()
+                    .map(|a| a.to_string())
+                    .chain(args.iter().cloned())
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        match args.first() {
+            Some(program) if !program.is_empty() => Ok(args),
+            _ => Err("empty command".to_string()),
+        }
+    }
+}
+
+impl ProcessSpec {
+    // the process started by -e or after --, the command is checked
+    pub fn from_command(command: CommandLine, shell: bool) -> Result<Self, String> {
+        command.args(shell)?;
+        Ok(Self {
+            name: "app".to_string(),
+            command,
+            env: BTreeMap::new(),
+            cwd: None,
+            restart: RestartPolicy::Always,
+            depends_on: Vec::new(),
+            health_check: None,
+            proxy: true,
+            shell,
+        })
+    }
+
+    // loads and validates a process list
+    pub fn load(path: &str) -> Result<Vec<ProcessSpec>, String> {
+        let config = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        ProcessSpec::parse(&config).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // a process list in start order: every process after its dependencies, in the order of
+    // the list otherwise. A single process is always the proxied one.
+    fn parse(config: &str) -> Result<Vec<ProcessSpec>, String> {
+        let config: ProcessConfig = serde_json::from_str(config).map_err(|e| e.to_string())?;
+        let mut specs = config.processes;
+        if specs.len() == 1 {
+            specs[0].proxy = true;
+        }
+
+        let mut names = HashMap::new();
+        for (i, spec) in specs.iter().enumerate() {
+            if let Err(e) = spec.command.args(spec.shell) {
+                return Err(format!("process {}: {}", spec.name, e));
+            }
+            if names.insert(spec.name.clone(), i).is_some() {
+                return Err(format!("process {}: duplicate name", spec.name));
+            }
+        }
+        if specs.iter().filter(|s| s.proxy).count() != 1 {
+            return Err("exactly one process must have \"proxy\": true".to_string());
+        }
+        for spec in specs.iter() {
+            if spec.proxy && spec.health_check.is_some() {
+                return Err(format!(
+                    "process {}: the proxied process is checked by --health-check",
+                    spec.name
+                ));
+            }
+            if let Some(dep) = spec.depends_on.iter().find(|d| !names.contains_key(*d)) {
+                return Err(format!("process {}: unknown dependency {}", spec.name, dep));
+            }
+        }
+
+        // dependency cycles and start order, depth first: 1 visiting, 2 visited
+        fn visit(
+            i: usize,
+            specs: &[ProcessSpec],
+            names: &HashMap<String, usize>,
+            marks: &mut Vec<u8>,
+            order: &mut Vec<usize>,
+        ) -> Result<(), String> {
+            match marks[i] {
+                1 => return Err(format!("process {}: dependency cycle", specs[i].name)),
+                2 => return Ok(()),
+                _ => {}
+            }
+            marks[i] = 1;
+            for dep in specs[i].depends_on.iter() {
+                visit(names[dep], specs, names, marks, order)?;
+            }
+            marks[i] = 2;
+            order.push(i);
+            Ok(())
+        }
+        let mut marks = vec![0; specs.len()];
+        let mut order = Vec::new();
+        for i in 0..specs.len() {
+            visit(i, &specs, &names, &mut marks, &mut order)?;
+        }
+        let mut specs: Vec<Option<ProcessSpec>> = specs.into_iter().map(Some).collect();
+        Ok(order.into_iter().filter_map(|i| specs[i].take()).collect())
+    }
+}
+
+// pre_exec hooks run in the order they are registered: the process gets its group or session,
+// joins its cgroup and gets its rlimits while it still has gasket's privileges, then becomes
+// its user
+fn configure(
+    command: &mut Command,
+    signals: &SignalSettings,
+    limits: &Limits,
+    privileges: &Privileges,
+    cgroup: Option<&ProcessCgroup>,
+) {
+    signals.configure(command);
+    limits.configure(command, cgroup);
+    privileges.configure(command);
 }
-const MAX_SPAWNS: u32 = 5;
 
 impl StaticProcessManager {
-    pub fn spawn_process(self: Self) {
-        let cmd = self.cmd.clone();
-
-        if self.cmd != "" {
-            info!("Spawning: {}", cmd);
-            let ms = self.max_spawns.clone();
-            let port = self.port.clone();
-            let _task = actix_web::rt::task::spawn_blocking(move || {
-                //let _task = std::thread::spawn(move || {
-                let arr_cmd: Vec<&str> = cmd.split_whitespace().collect();
-                let tx = self.pid_sender;
-
-                let cmd = arr_cmd[0].clone();
-
-                let cleanup_time = time::Duration::from_secs(1);
-                let mut respawn_counter = 0;
-                loop {
-                    let mut child = match Command::new(cmd)
-                        .args(&arr_cmd[1..arr_cmd.len()])
-                        .env("PORT", port.to_string())
-                        .spawn()
-                    {
-                        Ok(child) => child,
-                        Err(e) => {
-                            info!("Error: {}: {} - exiting", cmd, e);
-                            std::process::exit(-1);
+    pub fn spawn_process(self) {
+        let spec = self.spec.clone();
+        // checked when the process list is built
+        let arr_cmd = spec.command.args(spec.shell).unwrap_or_default();
+        info!("Spawning {}: {:?}", spec.name, arr_cmd);
+        let restart = self.restart.clone();
+        let _task = actix_web::rt::task::spawn_blocking(move || {
+            let state = self.state.clone();
+            let shutdown = self.shutdown.clone();
+            let name = &spec.name;
+
+            if arr_cmd.is_empty() {
+                info!("Process {} has an empty command", name);
+                shutdown.request(Some(127));
+                return;
+            }
+
+            let cgroup = self.limits.cgroups.get(name).cloned();
+            let mut history = RestartHistory::new(&restart);
+            let mut respawn_counter = 0;
+            loop {
+                if shutdown.is_requested() {
+                    return;
+                }
+                let oom_kills = limits::oom_kill_count(cgroup.as_ref());
+                let (mut pid, mut exited) =
+                    match self.start(&arr_cmd, self.ports.upstream(), cgroup.as_ref()) {
+                        Ok(started) => started,
+                        Err((e, exit_code)) => {
+                            info!("Error: {}: {} - exiting", name, e);
+                            shutdown.request(Some(exit_code));
+                            return;
                         }
                     };
-                    info!("Spawned process pid: {}", child.id());
+                {
+                    let mut state = state.lock().unwrap();
+                    state.running = true;
+                    state.pid = Some(pid);
+                    state.started_at = Some(chrono::Local::now());
+                }
+                if self.track_readiness {
+                    self.readiness.set(true);
+                }
 
-                    match tx
-                        .lock()
-                        .unwrap()
-                        .blocking_send((child.id()).try_into().unwrap())
+                // the exit status is collected by the reaper, rolling restarts replace the
+                // process meanwhile
+                let mut requests = self.requests.as_ref().map(|r| r.lock().unwrap());
+                let exit_status = loop {
+                    let request = match requests.as_mut() {
+                        Some(requests) => {
+                            let received = Box::pin(requests.recv());
+                            match futures::executor::block_on(futures::future::select(
+                                &mut exited,
+                                received,
+                            )) {
+                                Either::Left((exit_status, _)) => break exit_status,
+                                Either::Right((Some(request), _)) => request,
+                                Either::Right((None, exited)) => {
+                                    break futures::executor::block_on(exited)
+                                }
+                            }
+                        }
+                        None => break futures::executor::block_on(&mut exited),
+                    };
+                    if let Some(started) =
+                        self.rolling_restart(request, &arr_cmd, cgroup.as_ref(), pid, &mut exited)
                     {
-                        Ok(_) => info!("{}", child.id()),
-                        Err(e) => info!("Error {}", e),
+                        pid = started.0;
+                        exited = started.1;
+                        let mut state = state.lock().unwrap();
+                        state.pid = Some(pid);
+                        state.started_at = Some(chrono::Local::now());
                     }
-
-                    match child.wait() {
-                        Ok(c) => match c.code() {
-                            Some(code) => info!("Process exited with status code: {}", code),
-                            None => info!("Process terminated by signal"),
-                        },
-                        Err(e) => info!("{}", e),
+                };
+                drop(requests);
+                // a SIGKILL while the OOM kill count went up
+                let oom_killed = exit_status
+                    .as_ref()
+                    .is_ok_and(|c| c.signal() == Some(libc::SIGKILL))
+                    && limits::oom_kill_count(cgroup.as_ref()) > oom_kills;
+                if oom_killed {
+                    info!("Process {} was killed by the OOM killer", name);
+                }
+                {
+                    let mut state = state.lock().unwrap();
+                    state.running = false;
+                    state.pid = None;
+                    if let Ok(c) = exit_status.as_ref() {
+                        state.last_exit_code = c.code();
+                        state.last_exit_signal = c.signal();
+                        state.last_exit_reason = Some(
+                            match (c.code(), oom_killed) {
+                                (_, true) => "oom_kill",
+                                (Some(_), _) => "exit",
+                                (None, _) => "signal",
+                            }
+                            .to_string(),
+                        );
                     }
+                    if oom_killed {
+                        state.oom_kills += 1;
+                    }
+                }
+                if self.track_readiness {
+                    self.readiness.set(false);
+                }
+                // exit code as seen by a shell: 128 + signal for signal deaths
+                let exit_code = match exit_status {
+                    Ok(c) => match (c.code(), c.signal()) {
+                        (Some(code), _) => {
+                            info!("Process {} exited with status code: {}", name, code);
+                            code
+                        }
+                        (None, Some(signal)) => {
+                            info!("Process {} terminated by signal {}", name, signal);
+                            128 + signal
+                        }
+                        (None, None) => -1,
+                    },
+                    Err(e) => {
+                        info!("{}", e);
+                        -1
+                    }
+                };
+                let success = exit_code == 0;
+                if shutdown.is_requested() {
+                    if spec.proxy {
+                        shutdown.set_exit_code(exit_code);
+                    }
+                    info!("Process {} stopped", name);
+                    return;
+                }
 
-                    respawn_counter = respawn_counter + 1;
-                    if respawn_counter > ms {
-                        info!("Process spawning too much, aborting gasket");
-                        std::process::exit(-1);
+                let completed = match spec.restart {
+                    RestartPolicy::Never => {
+                        info!("Process {} not restarted (restart: never)", name);
+                        true
                     }
-                    info!("Sleeping before respawn {}", respawn_counter);
-                    thread::sleep(cleanup_time);
+                    RestartPolicy::OnFailure if success => {
+                        info!("Process {} completed (restart: on-failure)", name);
+                        true
+                    }
+                    _ => false,
+                };
+                if completed {
+                    state.lock().unwrap().completed = true;
+                    // nothing left to proxy to
+                    if spec.proxy {
+                        info!("Proxied process {} completed, stopping gasket", name);
+                        shutdown.request(Some(exit_code));
+                    }
+                    return;
                 }
-            });
-        };
-    }
 
-    pub async fn run(cmd: String) -> signal_hook_tokio::Handle {
-        let port = env::var("PORT")
-            .map(|s| s.parse().unwrap_or(3000))
-            .unwrap_or(3000);
+                let uptime = state
+                    .lock()
+                    .unwrap()
+                    .started_at
+                    .map(|started_at| chrono::Local::now() - started_at)
+                    .unwrap_or_else(chrono::Duration::zero);
+                let backoff = match history.next(&restart, time::Instant::now(), uptime) {
+                    Some(backoff) => backoff,
+                    None => {
+                        info!(
+                            "Process {} restarted {} times in {}s, restart budget exceeded",
+                            name,
+                            history.restarts.len(),
+                            restart.window.num_seconds()
+                        );
+                        match restart.exceeded {
+                            RestartExceeded::Exit => {
+                                info!("Aborting gasket with exit code {}", exit_code);
+                                shutdown.request(Some(exit_code));
+                            }
+                            RestartExceeded::StayUp => {
+                                info!("Process {} not restarted anymore", name);
+                            }
+                        }
+                        return;
+                    }
+                };
+
+                respawn_counter += 1;
+                state.lock().unwrap().restarts = respawn_counter;
+                info!(
+                    "Sleeping {}ms before respawn {} ({})",
+                    backoff.num_milliseconds(),
+                    respawn_counter,
+                    if oom_killed { "OOM killed" } else { "exited" }
+                );
+                thread::sleep(backoff.to_std().unwrap_or_default());
+            }
+        });
+    }
 
-        let (tx, rx) = tokio::sync::mpsc::channel(10);
+    // starts the process, the proxied one listening on port. Err: why, and the exit code
+    fn start(
+        &self,
+        args: &[String],
+        port: u16,
+        cgroup: Option<&ProcessCgroup>,
+    ) -> Result<(u32, oneshot::Receiver<std::process::ExitStatus>), (String, i32)> {
+        let spec = &self.spec;
+        let name = &spec.name;
+        let mut env_settings = self.env.clone();
+        if spec.proxy {
+            env_settings
+                .settings
+                .insert("GASKET_UPSTREAM_PORT".to_string(), port.to_string());
+        }
+        let env = env_settings.build(name, &spec.env).map_err(|e| (e, 1))?;
+        let mut command = Command::new(&args[0]);
+        command.args(&args[1..]).env_clear().envs(&env);
+        if spec.proxy {
+            for name in self.ports.env.iter() {
+                command.env(name, port.to_string());
+            }
+        }
+        if let Some(cwd) = spec.cwd.as_ref() {
+            command.current_dir(cwd);
+        }
+        if self.output.is_some() {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        configure(
+            &mut command,
+            &self.signals,
+            &self.limits,
+            &self.privileges,
+            cgroup,
+        );
+        let (mut child, exited) = reaper::spawn(&mut command).map_err(|e| {
+            // same exit codes as a shell for commands not found or not executable
+            let exit_code = match e.kind() {
+                std::io::ErrorKind::NotFound => 127,
+                std::io::ErrorKind::PermissionDenied => 126,
+                _ => 1,
+            };
+            (format!("{}: {}", args[0], e), exit_code)
+        })?;
+        info!("Spawned process {} pid: {}", name, child.id());
+        if let Some(output) = self.output.as_ref() {
+            if let Some(stdout) = child.stdout.take() {
+                capture(output, name, child.id(), Stream::Stdout, stdout);
+            }
+            if let Some(stderr) = child.stderr.take() {
+                capture(output, name, child.id(), Stream::Stderr, stderr);
+            }
+        }
+        Ok((child.id(), exited))
+    }
 
-        let s = Self {
-            self_pid: std::process::id(),
-            pid_receiver: Arc::new(Mutex::new(rx)),
-            pid_sender: Arc::new(Mutex::new(tx)),
-            port: port + 1, // increment port by 1
-            max_spawns: MAX_SPAWNS,
-            cmd: cmd,
+    // a rolling restart of the running process: a new one is started on the port of the
+    // request, the running one is stopped once requests go to the new one, otherwise the new
+    // one is killed. Returns the new process when it replaced the running one.
+    fn rolling_restart(
+        &self,
+        request: rolling_restart::Request,
+        args: &[String],
+        cgroup: Option<&ProcessCgroup>,
+        pid: u32,
+        exited: &mut oneshot::Receiver<std::process::ExitStatus>,
+    ) -> Option<(u32, oneshot::Receiver<std::process::ExitStatus>)> {
+        let name = &self.spec.name;
+        info!("Rolling restart of {} on port {}", name, request.port);
+        let (new_pid, mut new_exited) = match self.start(args, request.port, cgroup) {
+            Ok(started) => started,
+            Err((e, _)) => {
+                let _ = request.spawned.send(Err(e));
+                return None;
+            }
+        };
+        let _ = request.spawned.send(Ok(new_pid));
+        let switched = futures::executor::block_on(request.verdict).unwrap_or(false);
+        let (stopped_pid, stopped_exited) = if switched {
+            (pid, exited)
+        } else {
+            (new_pid, &mut new_exited)
         };
+        let signal = if switched {
+            self.signals.rewrite(self.signals.stop_signal)
+        } else {
+            Some(Signal(libc::SIGKILL))
+        };
+        match signal {
+            Some(signal) => {
+                info!(
+                    "Stopping process {} pid {} with {}",
+                    name,
+                    stopped_pid,
+                    signal.name()
+                );
+                self.signals.send(stopped_pid as libc::pid_t, signal.0);
+            }
+            None => info!(
+                "Stopping process {} pid {}, stop signal dropped",
+                name, stopped_pid
+            ),
+        }
+        let deadline =
+            time::Instant::now() + self.signals.stop_grace_period.to_std().unwrap_or_default();
+        loop {
+            match stopped_exited.try_recv() {
+                Err(oneshot::error::TryRecvError::Empty) if time::Instant::now() < deadline => {
+                    thread::sleep(time::Duration::from_millis(100));
+                }
+                Err(oneshot::error::TryRecvError::Empty) => {
+                    info!("Killing process {}", stopped_pid);
+                    self.signals.send(stopped_pid as libc::pid_t, libc::SIGKILL);
+                    let _ = futures::executor::block_on(stopped_exited);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        let _ = request.done.send(());
+        if switched {
+            info!("Rolling restart of {} done, pid {}", name, new_pid);
+            Some((new_pid, new_exited))
+        } else {
+            None
+        }
+    }
+}
 
-        info!("Spawn: env vars: PORT: {}", s.port);
-        let signals = Signals::new(&[SIGHUP, SIGTERM, SIGINT, SIGQUIT, SIGCHLD]).unwrap();
+impl ProcessSupervisor {
+    // starts each process once its dependencies are healthy, the proxied process is ready
+    // when proxy_readiness (--health-check) is, the others use their own health check with
+    // the --health-check-* timings
+    #[allow(clippy::too_many_arguments)]
+    pub async fn run(
+        specs: Vec<ProcessSpec>,
+        proxy_readiness: Arc<Readiness>,
+        health_check: &HealthCheckSettings,
+        restart: RestartSettings,
+        output: Option<OutputSettings>,
+        signals: SignalSettings,
+        privileges: Privileges,
+        env: EnvSettings,
+        ports: Arc<Ports>,
+        limits: Limits,
+        shutdown: Arc<Shutdown>,
+    ) -> Self {
+        let (restarter, requests) = Restarter::new(
+            ports.clone(),
+            HealthCheckSettings {
+                name: "Rolling restart".to_string(),
+                ..health_check.clone()
+            },
+            restart.rolling_timeout,
+            shutdown.clone(),
+        );
+        let mut requests = Some(Arc::new(Mutex::new(requests)));
+        let managers: Vec<StaticProcessManager> = specs
+            .into_iter()
+            .map(|spec| {
+                let (readiness, track_readiness) = match spec.health_check.as_ref() {
+                    _ if spec.proxy => (proxy_readiness.clone(), false),
+                    Some(check) => {
+                        let settings = HealthCheckSettings {
+                            name: format!("Process {}", spec.name),
+                            kind: check.kind,
+                            path: check.path.clone(),
+                            command: check.command.clone(),
+                            ..health_check.clone()
+                        };
+                        {
+                            let port = check.port;
+                            (HealthChecker::start(settings, move || port), false)
+                        }
+                    }
+                    None => (
+                        Arc::new(Readiness::new(
+                            &format!("Process {}", spec.name),
+                            false,
+                            chrono::Duration::zero(),
+                        )),
+                        true,
+                    ),
+                };
+                StaticProcessManager {
+                    ports: ports.clone(),
+                    restart: restart.clone(),
+                    state: Arc::new(Mutex::new(ProcessState {
+                        name: spec.name.clone(),
+                        proxied: spec.proxy,
+                        managed: true,
+                        ..Default::default()
+                    })),
+                    readiness,
+                    track_readiness,
+                    output: output.clone(),
+                    signals: signals.clone(),
+                    privileges: privileges.clone(),
+                    env: env.clone(),
+                    limits: limits.clone(),
+                    shutdown: shutdown.clone(),
+                    requests: if spec.proxy { requests.take() } else { None },
+                    spec,
+                }
+            })
+            .collect();
+
+        info!(
+            "Spawn: env vars: {}: {}",
+            ports.env.join(", "),
+            ports.upstream()
+        );
+        let received = Signals::new(FORWARDED.iter().chain(&[SIGTERM, SIGINT, SIGQUIT])).unwrap();
 
-        let handle = signals.handle();
+        let handle = received.handle();
 
-        s.clone().signals_handler(signals).await;
+        let states: Vec<Arc<Mutex<ProcessState>>> =
+            managers.iter().map(|m| m.state.clone()).collect();
+        ProcessSupervisor::signals_handler(
+            received,
+            states.clone(),
+            signals.clone(),
+            restarter.clone(),
+            shutdown.clone(),
+        )
+        .await;
 
-        s.clone().spawn_process(); // blocking process manager
-        return handle;
+        let readiness: HashMap<String, Arc<Readiness>> = managers
+            .iter()
+            .map(|m| (m.spec.name.clone(), m.readiness.clone()))
+            .collect();
+        for manager in managers {
+            let dependencies: Vec<(String, Arc<Readiness>)> = manager
+                .spec
+                .depends_on
+                .iter()
+                .map(|d| (d.clone(), readiness[d].clone()))
+                .collect();
+            actix_web::rt::spawn(async move {
+                for (name, readiness) in dependencies {
+                    if !readiness.is_ready() {
+                        info!("Process {} waiting for {}", manager.spec.name, name);
+                        let ready = Box::pin(readiness.wait());
+                        let stopped = Box::pin(manager.shutdown.wait());
+                        futures::future::select(ready, stopped).await;
+                    }
+                }
+                if manager.shutdown.is_requested() {
+                    return;
+                }
+                manager.spawn_process(); // blocking process manager
+            });
+        }
+        Self {
+            states,
+            restarter,
+            signals,
+            handle,
+        }
     }
 
-    async fn grim_reaper(&mut self, pid_t: i32) -> tokio::task::JoinHandle<()> {
-        let signal_task = actix_web::rt::spawn(async move {
-            let mut st = 0;
-            loop {
-                let lpid = unsafe { libc::waitpid(-1, &mut st, libc::WNOHANG) };
-                info!("Capturing zombie {}", lpid);
-                if lpid == pid_t {
-                    return ();
-                } else if lpid <= 0 {
-                    break;
+    // asks the running processes to exit with signal (rewritten), they are killed once the
+    // grace period is over. So are the processes they leave behind: their descendants, the
+    // members of their group or session, the orphans gasket adopted (they get the signal too,
+    // even once no process runs anymore) and, with kill_cgroup, the rest of gasket's cgroup.
+    // Everything killed is reaped before returning.
+    pub async fn stop(&self, signal: Signal, grace_period: chrono::Duration, kill_cgroup: bool) {
+        let pids = ProcessSupervisor::pids(&self.states);
+        let own = std::process::id() as libc::pid_t;
+        let adopted: Vec<libc::pid_t> = process_tree::children(own)
+            .into_iter()
+            .filter(|pid| !pids.contains(pid))
+            .collect();
+        // descendants are listed before they are re-parented to gasket
+        let descendants: Vec<libc::pid_t> = pids
+            .iter()
+            .chain(adopted.iter())
+            .flat_map(|pid| process_tree::descendants(*pid))
+            .chain(adopted.iter().copied())
+            .collect();
+        let leftovers = || {
+            let mut leftovers = descendants.clone();
+            for pid in &pids {
+                leftovers.extend(self.signals.members(*pid));
+            }
+            if kill_cgroup {
+                leftovers.extend(process_tree::cgroup_members().unwrap_or_default());
+            }
+            leftovers.sort_unstable();
+            leftovers.dedup();
+            leftovers.retain(|pid| !pids.contains(pid));
+            process_tree::alive(&leftovers)
+        };
+        if pids.is_empty() && leftovers().is_empty() {
+            return;
+        }
+        match self.signals.rewrite(signal) {
+            Some(signal) => {
+                if !pids.is_empty() {
+                    info!("Stopping processes {:?} with {}", pids, signal.name());
+                }
+                for pid in &pids {
+                    self.signals.send(*pid, signal.0);
+                }
+                for pid in &adopted {
+                    info!("Stopping orphan process {} with {}", pid, signal.name());
+                    unsafe { libc::kill(*pid, signal.0) };
                 }
             }
-            ()
-        });
-        signal_task
+            None => info!("Stopping processes {:?}, {} dropped", pids, signal.name()),
+        }
+        let stopped = async {
+            while !ProcessSupervisor::pids(&self.states).is_empty() || !leftovers().is_empty() {
+                actix_web::rt::time::sleep(time::Duration::from_millis(100)).await;
+            }
+        };
+        let timeout = grace_period.to_std().unwrap_or_default();
+        if actix_web::rt::time::timeout(timeout, stopped)
+            .await
+            .is_err()
+        {
+            for pid in ProcessSupervisor::pids(&self.states) {
+                info!("Killing process {}", pid);
+                self.signals.send(pid, libc::SIGKILL);
+            }
+            process_tree::kill(&leftovers());
+            while !ProcessSupervisor::pids(&self.states).is_empty() {
+                actix_web::rt::time::sleep(time::Duration::from_millis(100)).await;
+            }
+            let killed = async {
+                while !leftovers().is_empty() {
+                    actix_web::rt::time::sleep(time::Duration::from_millis(10)).await;
+                }
+            };
+            let _ = actix_web::rt::time::timeout(time::Duration::from_secs(1), killed).await;
+        }
+        reaper::reap();
     }
 
-    async fn signals_handler(mut self, signals: Signals) -> tokio::task::JoinHandle<()> {
-        let rx = Arc::clone(&self.pid_receiver);
+    // exit code of the proxied process, 0 while it runs
+    pub fn exit_code(&self) -> i32 {
+        self.states
+            .iter()
+            .map(|s| s.lock().unwrap())
+            .find(|s| s.proxied)
+            .and_then(|s| match (s.last_exit_code, s.last_exit_signal) {
+                (Some(code), _) => Some(code),
+                (None, Some(signal)) => Some(128 + signal),
+                (None, None) => None,
+            })
+            .unwrap_or(0)
+    }
+
+    pub fn close(&self) {
+        self.handle.close();
+    }
+
+    fn pids(states: &[Arc<Mutex<ProcessState>>]) -> Vec<libc::pid_t> {
+        states
+            .iter()
+            .filter_map(|s| s.lock().unwrap().pid)
+            .map(|pid| pid as libc::pid_t)
+            .collect()
+    }
+
+    async fn signals_handler(
+        received: Signals,
+        states: Vec<Arc<Mutex<ProcessState>>>,
+        signals: SignalSettings,
+        restarter: Restarter,
+        shutdown: Arc<Shutdown>,
+    ) -> tokio::task::JoinHandle<()> {
         let signal_task = actix_web::rt::spawn(async move {
-            let mut signals = signals.fuse();
-            while let Some(signal) = signals.next().await {
-                let pid = rx.lock().unwrap().recv().await.unwrap();
-                let pid_t: libc::pid_t = match pid.try_into() {
-                    Ok(x) => x,
-                    Err(_e) => -1,
-                };
-                self.grim_reaper(pid_t).await;
-                //GrimReaper::new().unwrap().reap(pid_t).await.unwrap();
+            let mut received = received.fuse();
+            while let Some(signal) = received.next().await {
                 match signal {
-                    SIGCHLD => {
-                        info!("SIGCHLD captured");
+                    SIGTERM | SIGINT | SIGQUIT => {
+                        shutdown.request(None);
                     }
-                    SIGHUP => {
-                        info!("SIGHUP");
+                    SIGHUP if signals.sighup == SighupAction::Restart => {
+                        let restarter = restarter.clone();
+                        actix_web::rt::spawn(async move {
+                            if let Err(e) = restarter.restart().await {
+                                info!("SIGHUP: rolling restart failed: {}", e);
+                            }
+                        });
+                    }
+                    SIGHUP if signals.sighup == SighupAction::Reload => {
+                        match crate::tls_utils::reload() {
+                            Ok(()) => info!("SIGHUP: TLS certificates reloaded"),
+                            Err(e) => info!("SIGHUP: TLS reload failed: {}", e),
+                        }
+                    }
+                    signal => {
+                        let signal = Signal(signal);
+                        match signals.rewrite(signal) {
+                            Some(forwarded) => {
+                                info!("Forwarding {} as {}", signal.name(), forwarded.name());
+                                for pid in ProcessSupervisor::pids(&states) {
+                                    signals.send(pid, forwarded.0);
+                                }
+                            }
+                            None => info!("{} dropped", signal.name()),
+                        }
                     }
-
-                    SIGINT => unsafe {
-                        libc::kill(pid_t, libc::SIGINT);
-                    },
-
-                    SIGTERM | SIGQUIT => unsafe {
-                        libc::kill(pid_t, libc::SIGTERM);
-                        std::process::exit(-1);
-                    },
-                    _ => unreachable!(),
                 }
             }
         });
         signal_task
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::privileges::Identity;
+
+    fn restart_settings(limit: u32) -> RestartSettings {
+        RestartSettings {
+            backoff_initial: chrono::Duration::milliseconds(100),
+            backoff_max: chrono::Duration::milliseconds(1000),
+            limit,
+            window: chrono::Duration::seconds(60),
+            exceeded: RestartExceeded::Exit,
+            rolling_timeout: chrono::Duration::seconds(60),
+        }
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let settings = restart_settings(0);
+        let mut history = RestartHistory::new(&settings);
+        let now = time::Instant::now();
+        let crashed = chrono::Duration::milliseconds(10);
+        let delays: Vec<i64> = (0..6)
+            .map(|_| history.next(&settings, now, crashed).unwrap())
+            .map(|delay| delay.num_milliseconds())
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        // a process that ran longer than the cap starts over
+        let stable = chrono::Duration::milliseconds(1500);
+        let delay = history.next(&settings, now, stable).unwrap();
+        assert_eq!(delay.num_milliseconds(), 100);
+        let delay = history.next(&settings, now, crashed).unwrap();
+        assert_eq!(delay.num_milliseconds(), 200);
+    }
+
+    #[test]
+    fn restart_budget() {
+        let settings = restart_settings(3);
+        let mut history = RestartHistory::new(&settings);
+        let t0 = time::Instant::now();
+        let at = |s: u64| t0 + time::Duration::from_secs(s);
+        let uptime = chrono::Duration::zero();
+        assert!(history.next(&settings, at(0), uptime).is_some());
+        assert!(history.next(&settings, at(10), uptime).is_some());
+        assert!(history.next(&settings, at(20), uptime).is_some());
+        assert!(history.next(&settings, at(30), uptime).is_none());
+        // the first restart leaves the window
+        assert!(history.next(&settings, at(61), uptime).is_some());
+        assert!(history.next(&settings, at(62), uptime).is_none());
+        // once the window is over the budget is whole again
+        assert!(history.next(&settings, at(200), uptime).is_some());
+        assert!(history.next(&settings, at(201), uptime).is_some());
+        assert!(history.next(&settings, at(202), uptime).is_some());
+        assert!(history.next(&settings, at(203), uptime).is_none());
+    }
+
+    fn start_order(processes: &str) -> Result<Vec<String>, String> {
+        let specs = ProcessSpec::parse(&format!("{{\"processes\": [{}]}}", processes))?;
+        Ok(specs.into_iter().map(|spec| spec.name).collect())
+    }
+
+    #[test]
+    fn dependency_order() {
+        let order = start_order(
+            r#"{"name": "app", "command": "app", "proxy": true, "depends_on": ["api", "cache"]},
+               {"name": "api", "command": "api", "depends_on": ["db"]},
+               {"name": "cache", "command": "cache"},
+               {"name": "db", "command": "db"},
+               {"name": "cron", "command": "cron"}"#,
+        );
+        assert_eq!(order.unwrap(), ["db", "api", "cache", "app", "cron"]);
+    }
+
+    #[test]
+    fn single_process_is_proxied() {
+        let specs =
+            ProcessSpec::parse(r#"{"processes": [{"name": "app", "command": "app"}]}"#).unwrap();
+        assert!(specs[0].proxy);
+    }
+
+    #[test]
+    fn invalid_process_lists() {
+        let rejected = |processes: &str, error: &str| {
+            let e = start_order(processes).unwrap_err();
+            assert!(e.contains(error), "{}: {}", processes, e);
+        };
+        rejected(
+            r#"{"name": "app", "command": "app", "proxy": true},
+               {"name": "app", "command": "other"}"#,
+            "process app: duplicate name",
+        );
+        rejected(
+            r#"{"name": "a", "command": "a", "proxy": true},
+               {"name": "b", "command": "b", "proxy": true}"#,
+            "exactly one process",
+        );
+        rejected(
+            r#"{"name": "a", "command": "a"}, {"name": "b", "command": "b"}"#,
+            "exactly one process",
+        );
+        rejected(
+            r#"{"name": "app", "command": "app", "proxy": true, "depends_on": ["db"]},
+               {"name": "cache", "command": "cache"}"#,
+            "process app: unknown dependency db",
+        );
+        rejected(
+            r#"{"name": "app", "command": "app", "proxy": true, "depends_on": ["a"]},
+               {"name": "a", "command": "a", "depends_on": ["b"]},
+               {"name": "b", "command": "b", "depends_on": ["a"]}"#,
+            "dependency cycle",
+        );
+        rejected(
+            r#"{"name": "app", "command": "app", "proxy": true, "depends_on": ["app"]},
+               {"name": "a", "command": "a"}"#,
+            "process app: dependency cycle",
+        );
+        rejected(
+            r#"{"name": "app", "command": "", "proxy": true}"#,
+            "process app: empty command",
+        );
+        rejected(
+            r#"{"name": "app", "command": "app", "proxy": true,
+                "health_check": {"kind": "tcp", "port": 1}},
+               {"name": "a", "command": "a"}"#,
+            "checked by --health-check",
+        );
+        rejected(
+            r#"{"name": "app", "command": "app", "proxy": true, "restart": "sometimes"}"#,
+            "unknown variant",
+        );
+    }
+
+    #[test]
+    fn limits_before_privileges() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        // a cgroup.procs only root can write: joining it as nobody would fail the spawn
+        let cgroup = std::env::temp_dir().join(format!("gasket-test-{}", std::process::id()));
+        fs::create_dir_all(&cgroup).unwrap();
+        fs::write(cgroup.join("cgroup.procs"), "").unwrap();
+        let mut command = Command::new("true");
+        let signals = SignalSettings {
+            rewrites: Vec::new(),
+            target: crate::signals::SignalTarget::Child,
+            sighup: SighupAction::Forward,
+            stop_signal: Signal(SIGTERM),
+            stop_grace_period: chrono::Duration::seconds(1),
+        };
+        let privileges = Privileges {
+            identity: Some(Identity {
+                uid: 65534,
+                gid: 65534,
+                groups: vec![65534],
+            }),
+            no_new_privs: false,
+        };
+        configure(
+            &mut command,
+            &signals,
+            &Limits::default(),
+            &privileges,
+            ProcessCgroup::new(cgroup.clone()).as_ref(),
+        );
+        let status = command.status();
+        let joined = fs::read_to_string(cgroup.join("cgroup.procs")).unwrap();
+        fs::remove_dir_all(&cgroup).unwrap();
+        assert!(status.unwrap().success());
+        assert_eq!(joined, "0");
+    }
+}
@@ -0,0 +1,120 @@
+use log::info;
+
+// Processes left behind by the supervised ones: daemons, workers started in the background,
+// children whose parent was killed. They are found from /proc (the descendants of a process,
+// the members of its group or session, gasket's own descendants: as a child subreaper it
+// adopts the orphans) or from the cgroup v2 gasket runs in, which holds every process it
+// started, even the ones that escaped to a new session.
+
+#[derive(Clone, Copy, Debug)]
+struct Stat {
+    pid: libc::pid_t,
+    ppid: libc::pid_t,
+    pgrp: libc::pid_t,
+    session: libc::pid_t,
+}
+
+// every live process from /proc/<pid>/stat, zombies waiting to be reaped are left out
+fn processes() -> Vec<Stat> {
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| {
+            let pid = entry
+                .ok()?
+                .file_name()
+                .to_str()?
+                .parse::<libc::pid_t>()
+                .ok()?;
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            // fields after the command name: state ppid pgrp session
+            let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+            if fields.next()? == "Z" {
+                return None;
+            }
+            Some(Stat {
+                pid,
+                ppid: fields.next()?.parse().ok()?,
+                pgrp: fields.next()?.parse().ok()?,
+                session: fields.next()?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+// processes of a session
+pub fn session_members(session: libc::pid_t) -> Vec<libc::pid_t> {
+    processes()
+        .iter()
+        .filter(|p| p.session == session)
+        .map(|p| p.pid)
+        .collect()
+}
+
+// processes of a group
+pub fn group_members(group: libc::pid_t) -> Vec<libc::pid_t> {
+    processes()
+        .iter()
+        .filter(|p| p.pgrp == group)
+        .map(|p| p.pid)
+        .collect()
+}
+
+// direct children of a process
+pub fn children(pid: libc::pid_t) -> Vec<libc::pid_t> {
+    processes()
+        .iter()
+        .filter(|p| p.ppid == pid)
+        .map(|p| p.pid)
+        .collect()
+}
+
+// children, grandchildren... of a process, itself excluded
+pub fn descendants(pid: libc::pid_t) -> Vec<libc::pid_t> {
+    let processes = processes();
+    let mut found = vec![pid];
+    let mut i = 0;
+    while i < found.len() {
+        let parent = found[i];
+        found.extend(processes.iter().filter(|p| p.ppid == parent).map(|p| p.pid));
+        i += 1;
+    }
+    found.remove(0);
+    found
+}
+
+// processes of the cgroup v2 gasket started in and of its sub-cgroups (the process cgroups),
+// gasket excluded. None when gasket is in the root cgroup (every process of the host) or
+// cgroups v2 are not mounted.
+pub fn cgroup_members() -> Option<Vec<libc::pid_t>> {
+    let base = crate::limits::base()?;
+    if crate::limits::is_root(base) {
+        return None;
+    }
+    let own = std::process::id() as libc::pid_t;
+    Some(
+        crate::limits::members(base)
+            .into_iter()
+            .filter(|pid| *pid != own)
+            .collect(),
+    )
+}
+
+// the processes still alive
+pub fn alive(pids: &[libc::pid_t]) -> Vec<libc::pid_t> {
+    let processes = processes();
+    pids.iter()
+        .filter(|pid| processes.iter().any(|p| p.pid == **pid))
+        .copied()
+        .collect()
+}
+
+// SIGKILLs the processes still alive, they are reaped by gasket as orphans
+pub fn kill(pids: &[libc::pid_t]) {
+    for pid in alive(pids) {
+        info!("Killing leftover process {}", pid);
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+    }
+}
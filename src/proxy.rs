@@ -1,17 +1,70 @@
+use crate::health_check::Readiness;
+use crate::http_utils::RequestIdSettings;
+use crate::metrics::Metrics;
+use crate::ports::Ports;
+use crate::routes::{RouteKey, RouteTable};
+use actix_web::http::{HeaderName, HeaderValue};
 use actix_web::{web, HttpRequest, HttpResponse, Result};
 use log::info;
-use std::sync::{Arc, Mutex};
+use std::str::FromStr;
+use std::sync::Arc;
 use url::Url;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn forward(
     req: HttpRequest,
     body: web::Bytes,
-    dest_port: web::Data<Arc<u16>>,
-    sp: web::Data<Arc<Mutex<crate::stability_patterns::StabilityPatterns>>>,
+    ports: web::Data<Arc<Ports>>,
+    sp: web::Data<Arc<crate::stability_patterns::StabilityPatterns>>,
+    routes: web::Data<Arc<RouteTable>>,
+    readiness: web::Data<Arc<Readiness>>,
+    metrics: web::Data<Arc<Metrics>>,
+    request_id: web::Data<Arc<RequestIdSettings>>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    info!("request proxy");
-    let dest_port = dest_port.as_ref();
-    let sp = sp.as_ref();
-    let forward_url = Url::parse(&format!("http://127.0.0.1:{}", dest_port)).unwrap();
-    crate::http_utils::Proxy::forward(req, body, &forward_url, sp.clone()).await
+    let id = request_id.request_id(&req);
+    info!("[{}] request proxy", id);
+    // stable key for the stability patterns, already resolved by the metrics middleware
+    let route_key = match req.extensions().get::<RouteKey>() {
+        Some(key) => key.0.clone(),
+        None => routes.key(req.path()),
+    };
+
+    // throttled requests are answered right away, the others are held while the child is
+    // starting or down, then given up on with 503
+    let mut res = match crate::http_utils::Proxy::throttle(&req, &sp, &metrics, &route_key) {
+        Err(throttled) => throttled,
+        Ok(_) if !readiness.wait_ready().await => {
+            info!("[{}] upstream not ready", id);
+            metrics.rejected(&route_key, "not_ready");
+            HttpResponse::ServiceUnavailable()
+                .insert_header(("retry-after", "1"))
+                .body("upstream not ready")
+        }
+        Ok(throttle_decision) => {
+            let dest_port = ports.upstream();
+            let sp = sp.as_ref();
+            let forward_url = Url::parse(&format!("http://127.0.0.1:{}", dest_port)).unwrap();
+
+            crate::http_utils::Proxy::forward(
+                req,
+                body,
+                &forward_url,
+                sp.clone(),
+                &metrics,
+                &route_key,
+                &request_id,
+                throttle_decision,
+            )
+            .await?
+        }
+    };
+
+    // every response carries the request id, including the ones answered by gasket
+    if let (Ok(name), Ok(value)) = (
+        HeaderName::from_str(&request_id.header),
+        HeaderValue::from_str(&id),
+    ) {
+        res.headers_mut().insert(name, value);
+    }
+    Ok(res)
 }
@@ -0,0 +1,66 @@
+use futures::stream::StreamExt;
+use log::info;
+use signal_hook::consts::signal::SIGCHLD;
+use signal_hook_tokio::Signals;
+use std::collections::BTreeMap;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+// PID1 zombie reaping: gasket is the only one waiting for its children. Each SIGCHLD drains
+// every exited child with waitpid(-1, WNOHANG): the exit status of a process spawned through
+// the reaper (supervised processes, exec health checks) is sent to its waiter, any other child
+// is an orphan re-parented to gasket and is only collected.
+// Children are spawned and registered while WAITERS is locked and the lock is held around each
+// waitpid, so a child exiting right away is never mistaken for an orphan.
+static WAITERS: Mutex<BTreeMap<u32, oneshot::Sender<ExitStatus>>> = Mutex::new(BTreeMap::new());
+
+// spawns a child, its exit status is received once it is reaped
+pub fn spawn(command: &mut Command) -> std::io::Result<(Child, oneshot::Receiver<ExitStatus>)> {
+    let mut waiters = WAITERS.lock().unwrap();
+    let child = command.spawn()?;
+    let (sender, receiver) = oneshot::channel();
+    waiters.insert(child.id(), sender);
+    Ok((child, receiver))
+}
+
+// collects every exited child
+pub fn reap() {
+    loop {
+        let mut waiters = WAITERS.lock().unwrap();
+        let mut status = 0;
+        let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+        if pid <= 0 {
+            return;
+        }
+        match waiters.remove(&(pid as u32)) {
+            Some(waiter) => {
+                let _ = waiter.send(ExitStatus::from_raw(status));
+            }
+            None => info!("Reaped orphan process {}", pid),
+        }
+    }
+}
+
+// reaps on every SIGCHLD, until handle is closed. Gasket becomes a child subreaper so orphans
+// are re-parented to it even when it does not run as PID1.
+pub fn start() -> signal_hook_tokio::Handle {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        info!(
+            "Can't become a child subreaper: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    let signals = Signals::new([SIGCHLD]).unwrap();
+    let handle = signals.handle();
+    actix_web::rt::spawn(async move {
+        // children that exited before the handler was registered
+        reap();
+        let mut signals = signals.fuse();
+        while signals.next().await.is_some() {
+            reap();
+        }
+    });
+    handle
+}
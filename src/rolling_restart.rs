@@ -0,0 +1,158 @@
+use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::health_check::{probe, HealthCheckKind, HealthCheckSettings, STARTUP_INTERVAL_MS};
+use crate::ports::{self, Ports};
+use crate::shutdown::Shutdown;
+
+// Rolling restart of the proxied process, without dropping requests:
+// 1. a new instance is started on a free port, next to the running one
+// 2. it is probed with the --health-check settings until it passes healthy_threshold checks in
+//    a row (a tcp probe without --health-check), or the timeout is over
+// 3. requests are switched to the new port, then the old instance gets the stop signal and the
+//    grace period like on shutdown
+// A new instance that never gets healthy is killed, the old one keeps serving. Triggered by the
+// admin endpoint or SIGHUP (--sighup restart), one at a time.
+
+// a rolling restart, handed to the thread managing the proxied process
+pub struct Request {
+    pub port: u16,
+    // pid of the new instance, or why it could not be started
+    pub spawned: oneshot::Sender<Result<u32, String>>,
+    // true: requests go to the new instance, the old one can be stopped
+    pub verdict: oneshot::Receiver<bool>,
+    // the old instance is stopped, or the new one killed
+    pub done: oneshot::Sender<()>,
+}
+
+#[derive(Clone)]
+pub struct Restarter {
+    requests: mpsc::UnboundedSender<Request>,
+    ports: Arc<Ports>,
+    health_check: HealthCheckSettings,
+    timeout: chrono::Duration,
+    in_progress: Arc<AtomicBool>,
+    shutdown: Arc<Shutdown>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RestartError {
+    InProgress,
+    Failed(String),
+}
+
+impl std::fmt::Display for RestartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestartError::InProgress => write!(f, "a rolling restart is in progress"),
+            RestartError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// clears in_progress however the restart ends
+struct InProgress(Arc<AtomicBool>);
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Restarter {
+    // a restarter and the receiving end for the manager of the proxied process
+    pub fn new(
+        ports: Arc<Ports>,
+        health_check: HealthCheckSettings,
+        timeout: chrono::Duration,
+        shutdown: Arc<Shutdown>,
+    ) -> (Self, mpsc::UnboundedReceiver<Request>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let health_check = match health_check.kind {
+            HealthCheckKind::None => HealthCheckSettings {
+                kind: HealthCheckKind::Tcp,
+                ..health_check
+            },
+            _ => health_check,
+        };
+        let restarter = Restarter {
+            requests: sender,
+            ports,
+            health_check,
+            timeout,
+            in_progress: Arc::new(AtomicBool::new(false)),
+            shutdown,
+        };
+        (restarter, receiver)
+    }
+
+    // restarts the proxied process, returns the port it now listens on
+    pub async fn restart(&self) -> Result<u16, RestartError> {
+        if self.in_progress.swap(true, Ordering::SeqCst) {
+            return Err(RestartError::InProgress);
+        }
+        let _in_progress = InProgress(self.in_progress.clone());
+        let failed = |e: &str| RestartError::Failed(e.to_string());
+        let port = ports::free().map_err(|e| failed(&e))?;
+        let (spawned, spawned_rx) = oneshot::channel();
+        let (verdict_tx, verdict) = oneshot::channel();
+        let (done, done_rx) = oneshot::channel();
+        self.requests
+            .send(Request {
+                port,
+                spawned,
+                verdict,
+                done,
+            })
+            .map_err(|_| failed("the proxied process is not running"))?;
+        let pid = spawned_rx
+            .await
+            .map_err(|_| failed("the proxied process is not running"))?
+            .map_err(RestartError::Failed)?;
+        info!("Rolling restart: new instance pid {} on port {}", pid, port);
+
+        let healthy = actix_web::rt::time::timeout(
+            self.timeout.to_std().unwrap_or_default(),
+            self.wait_healthy(port),
+        )
+        .await
+        .unwrap_or(false);
+        if healthy {
+            self.ports.set_upstream(port);
+            info!("Rolling restart: requests now go to port {}", port);
+        }
+        let _ = verdict_tx.send(healthy);
+        let _ = done_rx.await;
+        if healthy {
+            Ok(port)
+        } else {
+            Err(failed(&format!(
+                "the new instance on port {} did not get healthy",
+                port
+            )))
+        }
+    }
+
+    // probes the new instance until it passes healthy_threshold checks in a row
+    async fn wait_healthy(&self, port: u16) -> bool {
+        let mut successes = 0;
+        while !self.shutdown.is_requested() {
+            if probe(&self.health_check, port).await {
+                successes += 1;
+                if successes >= self.health_check.healthy_threshold {
+                    return true;
+                }
+            } else {
+                successes = 0;
+            }
+            let interval = std::cmp::min(
+                self.health_check.interval,
+                chrono::Duration::milliseconds(STARTUP_INTERVAL_MS),
+            );
+            actix_web::rt::time::sleep(interval.to_std().unwrap_or_default()).await;
+        }
+        false
+    }
+}
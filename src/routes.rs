@@ -0,0 +1,211 @@
+use regex::Regex;
+
+// Route keys: map request paths to a stable key shared by the stability patterns and metrics
+// so /users/1 and /users/2 land on the same state. Routes are matched in order:
+// - /users/{id}       template, {..} matches exactly one path segment
+// - /static/*         prefix, a trailing * matches any remaining segments
+// - ~^/api/(v[0-9]+)/ regex, matched against the whole path
+// A route can be named with name=pattern, regex names can reference groups ($1, ${name}).
+// Unnamed routes use the pattern itself as key. Unmatched paths go to the other bucket.
+
+enum Matcher {
+    Template(Vec<Segment>),
+    Regex(Regex),
+}
+
+enum Segment {
+    Literal(String),
+    Param,
+    Rest,
+}
+
+// route key of a request, set by the request metrics middleware
+#[derive(Clone, Debug)]
+pub struct RouteKey(pub String);
+
+pub struct Route {
+    name: String,
+    named: bool,
+    matcher: Matcher,
+}
+
+pub struct RouteTable {
+    routes: Vec<Route>,
+    other: String,
+}
+
+impl Route {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        // name=pattern, patterns always start with / or ~
+        let (name, pattern) = match spec.find('=') {
+            Some(i) if !spec.starts_with('/') && !spec.starts_with('~') => {
+                (Some(spec[..i].to_string()), &spec[i + 1..])
+            }
+            _ => (None, spec),
+        };
+
+        let matcher = if let Some(re) = pattern.strip_prefix('~') {
+            Matcher::Regex(Regex::new(re).map_err(|e| format!("route {}: {}", spec, e))?)
+        } else if let Some(template) = pattern.strip_prefix('/') {
+            let segments: Vec<&str> = template.split('/').collect();
+            let last = segments.len() - 1;
+            let mut parsed = Vec::new();
+            for (i, segment) in segments.iter().enumerate() {
+                if *segment == "*" {
+                    if i != last {
+                        return Err(format!("route {}: * must be the last segment", spec));
+                    }
+                    parsed.push(Segment::Rest);
+                } else if segment.starts_with('{') && segment.ends_with('}') {
+                    parsed.push(Segment::Param);
+                } else {
+                    parsed.push(Segment::Literal(segment.to_string()));
+                }
+            }
+            Matcher::Template(parsed)
+        } else {
+            return Err(format!(
+                "route {}: pattern must start with / or ~ (regex)",
+                spec
+            ));
+        };
+
+        Ok(Self {
+            named: name.is_some(),
+            name: name.unwrap_or_else(|| pattern.to_string()),
+            matcher,
+        })
+    }
+
+    fn key(&self, path: &str) -> Option<String> {
+        match &self.matcher {
+            Matcher::Regex(re) => {
+                let captures = re.captures(path)?;
+                if !self.named {
+                    return Some(self.name.clone());
+                }
+                let mut key = String::new();
+                captures.expand(&self.name, &mut key);
+                Some(key)
+            }
+            Matcher::Template(segments) => {
+                let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+                for (i, segment) in segments.iter().enumerate() {
+                    match segment {
+                        Segment::Rest => return Some(self.name.clone()),
+                        Segment::Param => match parts.get(i) {
+                            Some(p) if !p.is_empty() => {}
+                            _ => return None,
+                        },
+                        Segment::Literal(l) => {
+                            if parts.get(i) != Some(&l.as_str()) {
+                                return None;
+                            }
+                        }
+                    }
+                }
+                if parts.len() == segments.len() {
+                    Some(self.name.clone())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl RouteTable {
+    pub fn new(specs: &[String], other: String) -> Result<Self, String> {
+        let routes = specs
+            .iter()
+            .map(|spec| Route::parse(spec))
+            .collect::<Result<Vec<Route>, String>>()?;
+        Ok(Self { routes, other })
+    }
+
+    // metrics label of a key: without configured routes keys are raw paths, all reported as
+    // the other key
+    pub fn label<'a>(&'a self, key: &'a str) -> &'a str {
+        if self.routes.is_empty() {
+            &self.other
+        } else {
+            key
+        }
+    }
+
+    // without configured routes the raw path is the key (bounded by --max-keys)
+    pub fn key(&self, path: &str) -> String {
+        if self.routes.is_empty() {
+            return path.to_string();
+        }
+        self.routes
+            .iter()
+            .find_map(|route| route.key(path))
+            .unwrap_or_else(|| self.other.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(specs: &[&str]) -> RouteTable {
+        let specs: Vec<String> = specs.iter().map(|s| s.to_string()).collect();
+        RouteTable::new(&specs, "other".to_string()).unwrap()
+    }
+
+    #[test]
+    fn templates() {
+        let routes = table(&["/users/{id}", "/users/{id}/posts", "assets=/static/*"]);
+        assert_eq!(routes.key("/users/1"), "/users/{id}");
+        assert_eq!(routes.key("/users/2"), "/users/{id}");
+        assert_eq!(routes.key("/users/1/posts"), "/users/{id}/posts");
+        assert_eq!(routes.key("/static/css/site.css"), "assets");
+        assert_eq!(routes.key("/static/"), "assets");
+        // a parameter matches exactly one non empty segment
+        assert_eq!(routes.key("/users/"), "other");
+        assert_eq!(routes.key("/users/1/2"), "other");
+        assert_eq!(routes.key("/"), "other");
+    }
+
+    #[test]
+    fn trailing_slash() {
+        let routes = table(&["/users/{id}", "/health"]);
+        assert_eq!(routes.key("/health"), "/health");
+        assert_eq!(routes.key("/health/"), "other");
+        assert_eq!(routes.key("/users/1/"), "other");
+    }
+
+    #[test]
+    fn regexes() {
+        let routes = table(&["api_$1=~^/api/(v[0-9]+)/", "~^/legacy/"]);
+        assert_eq!(routes.key("/api/v1/users"), "api_v1");
+        assert_eq!(routes.key("/api/v2/orders/3"), "api_v2");
+        assert_eq!(routes.key("/legacy/page"), "~^/legacy/");
+        assert_eq!(routes.key("/api/latest/users"), "other");
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let routes = table(&["me=/users/me", "/users/{id}"]);
+        assert_eq!(routes.key("/users/me"), "me");
+        assert_eq!(routes.key("/users/3"), "/users/{id}");
+    }
+
+    #[test]
+    fn invalid_routes() {
+        assert!(Route::parse("/static/*/css").is_err());
+        assert!(Route::parse("users/{id}").is_err());
+        assert!(Route::parse("~^/api/(").is_err());
+    }
+
+    #[test]
+    fn labels() {
+        let raw = table(&[]);
+        assert_eq!(raw.key("/users/1"), "/users/1");
+        assert_eq!(raw.label("/users/1"), "other");
+        let routes = table(&["/users/{id}"]);
+        assert_eq!(routes.label("/users/{id}"), "/users/{id}");
+        assert_eq!(routes.label("other"), "other");
+    }
+}
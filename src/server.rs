@@ -1,12 +1,261 @@
-use actix_web::{middleware, web, App, HttpServer};
+use crate::access_log::{AccessLog, AccessLogSettings, AccessLogger};
+use crate::admin::HealthState;
+use crate::http_utils::RequestIdSettings;
+use crate::metrics::{Metrics, RequestMetrics};
+use crate::ports::Ports;
+use crate::privileges::Identity;
+use crate::routes::RouteTable;
+use crate::shutdown::Shutdown;
+use crate::stability_patterns::{
+    CircuitBreakerSettings, ConcurrencySettings, RetrySettings, StabilityPatterns,
+    ThrottlerSettings,
+};
+use crate::telemetry::{RequestTracing, Tracer};
+use actix_web::dev::Server;
+use actix_web::{web, App, HttpServer};
 use log::info;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+
+const DRAIN_FLUSH_MS: u64 = 100;
+
+fn route_table(gasket_options: &crate::GasketOptions) -> Arc<RouteTable> {
+    match RouteTable::new(&gasket_options.routes, gasket_options.route_other.clone()) {
+        Ok(routes) => Arc::new(routes),
+        Err(e) => {
+            info!("Routes Abort: {}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+// identity gasket becomes once serving, checked at startup
+fn drop_privileges_to(gasket_options: &crate::GasketOptions) -> Option<Identity> {
+    if !gasket_options.drop_privileges {
+        return None;
+    }
+    match Identity::resolve(
+        gasket_options.user.as_deref()?,
+        gasket_options.group.as_deref(),
+        &gasket_options.supplementary_groups,
+    ) {
+        Ok(identity) => Some(identity),
+        Err(e) => {
+            info!("Privileges Abort: {}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+fn request_id_settings(gasket_options: &crate::GasketOptions) -> Arc<RequestIdSettings> {
+    let header = gasket_options.request_id_header.to_lowercase();
+    if actix_web::http::HeaderName::from_lowercase(header.as_bytes()).is_err() {
+        info!("Request Id Abort: invalid header {}", header);
+        std::process::exit(-1);
+    }
+    Arc::new(RequestIdSettings {
+        accepted_headers: gasket_options
+            .request_id_accept
+            .iter()
+            .map(|h| h.trim().to_lowercase())
+            .collect(),
+        header,
+        max_length: gasket_options.request_id_max_length,
+    })
+}
+
+fn access_logger(
+    gasket_options: &crate::GasketOptions,
+    sp: Arc<StabilityPatterns>,
+) -> Arc<AccessLogger> {
+    let settings = AccessLogSettings {
+        format: gasket_options.access_log,
+        fields: gasket_options.access_log_fields.clone(),
+        template: gasket_options.access_log_template.clone(),
+        samples: gasket_options.access_log_samples.clone(),
+    };
+    match AccessLogger::new(settings, sp) {
+        Ok(logger) => Arc::new(logger),
+        Err(e) => {
+            info!("Access Log Abort: {}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+fn stability_patterns(gasket_options: &crate::GasketOptions) -> Arc<StabilityPatterns> {
+    let circuitbreaker_settings = if gasket_options.circuitbreaker_enabled {
+        info!(
+            "Circuit breaker enabled: opens after {} errors for {}s",
+            gasket_options.circuitbreaker_max_trips, gasket_options.circuitbreaker_reset_timeout
+        );
+        Some(CircuitBreakerSettings {
+            max_trips: gasket_options.circuitbreaker_max_trips,
+            reset_timeout: chrono::Duration::seconds(
+                gasket_options.circuitbreaker_reset_timeout as i64,
+            ),
+        })
+    } else {
+        None
+    };
+    let throttler_settings = if gasket_options.throttling_enabled {
+        info!(
+            "Throttling enabled: {:?} by {:?}, {} requests per {}s",
+            gasket_options.throttling_algorithm,
+            gasket_options.throttling_key,
+            gasket_options.throttling_limit,
+            gasket_options.throttling_window
+        );
+        Some(ThrottlerSettings {
+            algorithm: gasket_options.throttling_algorithm,
+            key: gasket_options.throttling_key,
+            header: gasket_options.throttling_header.to_lowercase(),
+            max_requests: gasket_options.throttling_limit,
+            time_window: chrono::Duration::seconds(gasket_options.throttling_window as i64),
+        })
+    } else {
+        None
+    };
+    let retry_settings = if gasket_options.backoff_enabled {
+        info!(
+            "Retries enabled: {} attempts for {:?} with {:?} jitter",
+            gasket_options.retry_max_attempts,
+            gasket_options.retry_methods,
+            gasket_options.retry_jitter
+        );
+        Some(RetrySettings {
+            methods: gasket_options
+                .retry_methods
+                .iter()
+                .map(|m| m.trim().to_uppercase())
+                .collect(),
+            status_codes: gasket_options.retry_status.clone(),
+            max_attempts: gasket_options.retry_max_attempts,
+            base_delay: chrono::Duration::milliseconds(gasket_options.retry_base_delay as i64),
+            max_delay: chrono::Duration::milliseconds(gasket_options.retry_max_delay as i64),
+            jitter: gasket_options.retry_jitter,
+            deadline: chrono::Duration::milliseconds(gasket_options.retry_deadline as i64),
+            budget_ratio: gasket_options.retry_budget as f64 / 100.0,
+            budget_min_per_second: gasket_options.retry_budget_min,
+        })
+    } else {
+        None
+    };
+    let concurrency_settings = if gasket_options.concurrency_limit > 0 {
+        info!(
+            "Concurrency limit enabled: {} in-flight requests ({:?}), queue of {}",
+            gasket_options.concurrency_limit,
+            gasket_options.concurrency_mode,
+            gasket_options.concurrency_queue
+        );
+        Some(ConcurrencySettings {
+            mode: gasket_options.concurrency_mode,
+            initial_limit: gasket_options.concurrency_limit,
+            min_limit: gasket_options.concurrency_min_limit,
+            max_limit: gasket_options.concurrency_max_limit,
+            max_queue: gasket_options.concurrency_queue,
+            queue_timeout: chrono::Duration::milliseconds(
+                gasket_options.concurrency_queue_timeout as i64,
+            ),
+        })
+    } else {
+        None
+    };
+    let timeout = chrono::Duration::seconds(gasket_options.upstream_timeout as i64);
+    let idle_timeout = chrono::Duration::seconds(gasket_options.key_idle_timeout as i64);
+    let sp = Arc::new(StabilityPatterns::new(
+        circuitbreaker_settings,
+        throttler_settings,
+        retry_settings,
+        concurrency_settings,
+        timeout,
+        gasket_options.max_keys,
+        idle_timeout,
+    ));
+
+    // sweep idle keys in the background so unique paths or clients do not pile up
+    let evictor = sp.clone();
+    actix_web::rt::spawn(async move {
+        let period = std::time::Duration::from_secs(std::cmp::max(
+            (idle_timeout.num_seconds() / 2) as u64,
+            1,
+        ));
+        let mut interval = actix_web::rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            evictor.evict_idle();
+        }
+    });
+    sp
+}
+
+// runs a server until gasket shuts down: requests are still served for the prestop delay (the
+// readiness probe already fails), then new connections are refused and in-flight requests are
+// given up to the drain timeout to complete. The drain is the whole budget: the server is then
+// stopped without waiting for its workers again.
+async fn serve(
+    server: Server,
+    drop_to: Option<Identity>,
+    shutdown: Arc<Shutdown>,
+    metrics: Arc<Metrics>,
+    prestop_delay: u64,
+    drain_timeout: u64,
+) -> std::result::Result<(), std::io::Error> {
+    // the listener is bound and the TLS material loaded
+    if let Some(identity) = drop_to {
+        if let Err(e) = identity.drop_privileges() {
+            info!("Privileges Abort: {}", e);
+            std::process::exit(-1);
+        }
+    }
+    let handle = server.clone();
+    actix_web::rt::spawn(async move {
+        shutdown.wait().await;
+        if prestop_delay > 0 {
+            info!("Waiting {}s before stopping the server", prestop_delay);
+            actix_web::rt::time::sleep(std::time::Duration::from_secs(prestop_delay)).await;
+        }
+        handle.pause().await;
+        info!("Draining {} requests", metrics.in_flight());
+        let drained = async {
+            while metrics.in_flight() > 0 {
+                actix_web::rt::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+        };
+        let timeout = std::time::Duration::from_secs(drain_timeout);
+        if actix_web::rt::time::timeout(timeout, drained)
+            .await
+            .is_err()
+        {
+            info!("Drain timeout, dropping {} requests", metrics.in_flight());
+        }
+        // responses of the last requests are still being written
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(DRAIN_FLUSH_MS)).await;
+        info!("Stopping server");
+        handle.stop(false).await;
+    });
+    server.await
+}
 
 pub async fn mtls_server(
     gasket_options: crate::GasketOptions,
-    dest_port: Arc<u16>,
+    ports: Arc<Ports>,
     listen_addr: String,
+    health: Arc<HealthState>,
+    metrics: Arc<Metrics>,
+    tracer: Option<Arc<Tracer>>,
+    shutdown: Arc<Shutdown>,
 ) -> std::result::Result<(), std::io::Error> {
+    let sp = stability_patterns(&gasket_options);
+    let routes = route_table(&gasket_options);
+    metrics.watch_stability_patterns(sp.clone());
+    metrics.watch_routes(routes.clone());
+    let access_logger = access_logger(&gasket_options, sp.clone());
+    let request_id = request_id_settings(&gasket_options);
+    let admin_on_listener = gasket_options.admin_port == 0;
+    let prestop_delay = gasket_options.prestop_delay;
+    let drain_timeout = gasket_options.drain_timeout;
+    let metrics_handle = metrics.clone();
+    let drop_to = drop_privileges_to(&gasket_options);
     let private_key_path = match gasket_options.private_key_path {
         Some(cert_path) => {
             info!("Private key path: {:?}", cert_path);
@@ -29,15 +278,16 @@ pub async fn mtls_server(
         }
         None => "client_cert_path.pem".to_string(),
     };
-    let sp = Arc::new(Mutex::new(
-        crate::stability_patterns::StabilityPatterns::new(),
-    ));
-    // mTLS builder
-    let builder = match crate::tls_utils::CertificateManager::new_mtls_builder(
-        private_key_path,
-        certificate_chain_path,
-        client_ca_path,
-    ) {
+    health.watch_certificate(certificate_chain_path.clone());
+    health.watch_certificate(client_ca_path.clone());
+    // mTLS builder, built again on reload
+    let builder = match crate::tls_utils::reloadable(Box::new(move || {
+        crate::tls_utils::CertificateManager::new_mtls_builder(
+            private_key_path.clone(),
+            certificate_chain_path.clone(),
+            client_ca_path.clone(),
+        )
+    })) {
         Ok(b) => b,
         Err(e) => {
             info!("mTLS Abort: {}", e);
@@ -48,25 +298,59 @@ pub async fn mtls_server(
     info!("Starting mTLS server");
     let s = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(dest_port.clone()))
+            .app_data(web::Data::new(ports.clone()))
             .app_data(web::Data::new(sp.clone()))
-            .wrap(middleware::Logger::default())
+            .app_data(web::Data::new(routes.clone()))
+            .app_data(web::Data::new(health.readiness.clone()))
+            .app_data(web::Data::new(metrics.clone()))
+            .app_data(web::Data::new(request_id.clone()))
+            .configure(|cfg| {
+                if admin_on_listener {
+                    crate::admin::endpoints(cfg, &health, &metrics)
+                }
+            })
+            .wrap(RequestMetrics::new(metrics.clone(), routes.clone()))
+            .wrap(RequestTracing::new(tracer.clone()))
+            .wrap(AccessLog::new(access_logger.clone()))
             .default_service(web::route().to(crate::proxy::forward))
     })
+    .on_connect(crate::tls_utils::peer_identity_on_connect)
     .disable_signals()
     .workers(12)
-    .bind_openssl(listen_addr.clone(), builder)
+    .bind_openssl(listen_addr, builder)
     .unwrap()
-    .run()
-    .await;
-    return s;
+    .run();
+    serve(
+        s,
+        drop_to,
+        shutdown,
+        metrics_handle,
+        prestop_delay,
+        drain_timeout,
+    )
+    .await
 }
 
 pub async fn tls_server(
     gasket_options: crate::GasketOptions,
-    dest_port: Arc<u16>,
+    ports: Arc<Ports>,
     listen_addr: String,
+    health: Arc<HealthState>,
+    metrics: Arc<Metrics>,
+    tracer: Option<Arc<Tracer>>,
+    shutdown: Arc<Shutdown>,
 ) -> std::result::Result<(), std::io::Error> {
+    let sp = stability_patterns(&gasket_options);
+    let routes = route_table(&gasket_options);
+    metrics.watch_stability_patterns(sp.clone());
+    metrics.watch_routes(routes.clone());
+    let access_logger = access_logger(&gasket_options, sp.clone());
+    let request_id = request_id_settings(&gasket_options);
+    let admin_on_listener = gasket_options.admin_port == 0;
+    let prestop_delay = gasket_options.prestop_delay;
+    let drain_timeout = gasket_options.drain_timeout;
+    let metrics_handle = metrics.clone();
+    let drop_to = drop_privileges_to(&gasket_options);
     let private_key_path = match gasket_options.private_key_path {
         Some(cert_path) => {
             info!("Private key path: {:?}", cert_path);
@@ -82,11 +366,14 @@ pub async fn tls_server(
         None => "certificate_chain.crt".to_string(),
     };
 
-    // TLS Builder
-    let builder = match crate::tls_utils::CertificateManager::new_tls_builder(
-        private_key_path,
-        certificate_chain_path,
-    ) {
+    health.watch_certificate(certificate_chain_path.clone());
+    // TLS Builder, built again on reload
+    let builder = match crate::tls_utils::reloadable(Box::new(move || {
+        crate::tls_utils::CertificateManager::new_tls_builder(
+            private_key_path.clone(),
+            certificate_chain_path.clone(),
+        )
+    })) {
         Ok(b) => b,
         Err(e) => {
             info!("TLS Abort: {}", e);
@@ -94,49 +381,109 @@ pub async fn tls_server(
         }
     };
 
-    let sp = Arc::new(Mutex::new(
-        crate::stability_patterns::StabilityPatterns::new(),
-    ));
-
     info!("Starting TLS server");
     let s = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(dest_port.clone()))
+            .app_data(web::Data::new(ports.clone()))
             .app_data(web::Data::new(sp.clone()))
-            .wrap(middleware::Logger::default())
+            .app_data(web::Data::new(routes.clone()))
+            .app_data(web::Data::new(health.readiness.clone()))
+            .app_data(web::Data::new(metrics.clone()))
+            .app_data(web::Data::new(request_id.clone()))
+            .configure(|cfg| {
+                if admin_on_listener {
+                    crate::admin::endpoints(cfg, &health, &metrics)
+                }
+            })
+            .wrap(RequestMetrics::new(metrics.clone(), routes.clone()))
+            .wrap(RequestTracing::new(tracer.clone()))
+            .wrap(AccessLog::new(access_logger.clone()))
             .default_service(web::route().to(crate::proxy::forward))
     })
     .disable_signals()
     .workers(12)
-    .bind_openssl(listen_addr.clone(), builder)
+    .bind_openssl(listen_addr, builder)
     .unwrap()
-    .run()
-    .await;
-    return s;
+    .run();
+    serve(
+        s,
+        drop_to,
+        shutdown,
+        metrics_handle,
+        prestop_delay,
+        drain_timeout,
+    )
+    .await
 }
 
 pub async fn http_server(
     gasket_options: crate::GasketOptions,
-    dest_port: Arc<u16>,
+    ports: Arc<Ports>,
     listen_addr: String,
+    health: Arc<HealthState>,
+    metrics: Arc<Metrics>,
+    tracer: Option<Arc<Tracer>>,
+    shutdown: Arc<Shutdown>,
 ) -> std::result::Result<(), std::io::Error> {
-    let sp = Arc::new(Mutex::new(
-        crate::stability_patterns::StabilityPatterns::new(),
-    ));
+    let sp = stability_patterns(&gasket_options);
+    let routes = route_table(&gasket_options);
+    metrics.watch_stability_patterns(sp.clone());
+    metrics.watch_routes(routes.clone());
+    let access_logger = access_logger(&gasket_options, sp.clone());
+    let request_id = request_id_settings(&gasket_options);
+    let admin_on_listener = gasket_options.admin_port == 0;
+    let prestop_delay = gasket_options.prestop_delay;
+    let drain_timeout = gasket_options.drain_timeout;
+    let metrics_handle = metrics.clone();
+    let drop_to = drop_privileges_to(&gasket_options);
     info!("Starting HTTP server");
     let s = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(dest_port.clone()))
+            .app_data(web::Data::new(ports.clone()))
             .app_data(web::Data::new(sp.clone()))
-            .wrap(middleware::Logger::default())
+            .app_data(web::Data::new(routes.clone()))
+            .app_data(web::Data::new(health.readiness.clone()))
+            .app_data(web::Data::new(metrics.clone()))
+            .app_data(web::Data::new(request_id.clone()))
+            .configure(|cfg| {
+                if admin_on_listener {
+                    crate::admin::endpoints(cfg, &health, &metrics)
+                }
+            })
+            .wrap(RequestMetrics::new(metrics.clone(), routes.clone()))
+            .wrap(RequestTracing::new(tracer.clone()))
+            .wrap(AccessLog::new(access_logger.clone()))
             .default_service(web::route().to(crate::proxy::forward))
     })
     .disable_signals()
     .workers(12)
     .bind(listen_addr)
     .unwrap()
-    .run()
-    .await;
+    .run();
+    serve(
+        s,
+        drop_to,
+        shutdown,
+        metrics_handle,
+        prestop_delay,
+        drain_timeout,
+    )
+    .await
+}
 
-    s
+// probes on their own port, away from the proxied traffic. Bound right away, before gasket
+// drops its privileges.
+pub fn admin_server(
+    health: Arc<HealthState>,
+    metrics: Arc<Metrics>,
+    listen_addr: String,
+) -> std::result::Result<Server, std::io::Error> {
+    info!("Starting admin server on {}", listen_addr);
+    Ok(HttpServer::new(move || {
+        App::new().configure(|cfg| crate::admin::endpoints(cfg, &health, &metrics))
+    })
+    .disable_signals()
+    .workers(1)
+    .bind(listen_addr)?
+    .run())
 }
@@ -0,0 +1,96 @@
+use chrono::{DateTime, Duration, Local};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+
+// Bounded map for per key state shared by all workers
+// - keys are spread over SHARDS independently locked maps so workers rarely contend
+// - each shard holds at most max_keys / SHARDS entries, the least recently used entry
+//   is evicted to make room for a new key
+// - entries not accessed for a while can be dropped with evict_idle
+const SHARDS: usize = 16;
+
+struct Entry<V> {
+    value: V,
+    last_access: DateTime<Local>,
+}
+
+pub struct ShardedMap<V> {
+    shards: Vec<Mutex<HashMap<String, Entry<V>>>>,
+    hasher: RandomState,
+    max_keys_per_shard: usize,
+}
+
+impl<V> ShardedMap<V> {
+    pub fn new(max_keys: usize) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            max_keys_per_shard: std::cmp::max(max_keys / SHARDS, 1),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<HashMap<String, Entry<V>>> {
+        &self.shards[(self.hasher.hash_one(key) as usize) % SHARDS]
+    }
+
+    // runs f on the value for key, creating it with create if it does not exist
+    pub fn with<R>(&self, key: &str, create: impl FnOnce() -> V, f: impl FnOnce(&mut V) -> R) -> R {
+        let now = Local::now();
+        let mut shard = self.shard(key).lock().unwrap();
+        if !shard.contains_key(key) {
+            if shard.len() >= self.max_keys_per_shard {
+                let lru = shard
+                    .iter()
+                    .min_by_key(|(_, e)| e.last_access)
+                    .map(|(k, _)| k.clone());
+                if let Some(lru) = lru {
+                    shard.remove(&lru);
+                }
+            }
+            shard.insert(
+                key.to_string(),
+                Entry {
+                    value: create(),
+                    last_access: now,
+                },
+            );
+        }
+        let entry = shard.get_mut(key).unwrap();
+        entry.last_access = now;
+        f(&mut entry.value)
+    }
+
+    // runs f on the value for key if it exists
+    pub fn get_with<R>(&self, key: &str, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        let mut shard = self.shard(key).lock().unwrap();
+        shard.get_mut(key).map(|entry| {
+            entry.last_access = Local::now();
+            f(&mut entry.value)
+        })
+    }
+
+    // maps every entry with f, without touching their last access
+    pub fn snapshot<R>(&self, f: impl Fn(&V) -> R) -> Vec<(String, R)> {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            entries.extend(shard.iter().map(|(k, e)| (k.clone(), f(&e.value))));
+        }
+        entries
+    }
+
+    // drops entries not accessed for idle, returns how many were dropped
+    pub fn evict_idle(&self, idle: Duration) -> usize {
+        let oldest = Local::now() - idle;
+        let mut evicted = 0;
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let before = shard.len();
+            shard.retain(|_, e| e.last_access >= oldest);
+            evicted += before - shard.len();
+        }
+        evicted
+    }
+}
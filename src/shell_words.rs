@@ -0,0 +1,111 @@
+// Splits a command line into arguments the way a POSIX shell does, without expanding anything:
+// 'single quotes' are literal, "double quotes" only escape \" \\ \$ \` and a newline, a
+// backslash outside quotes escapes the next character. "" and '' give an empty argument.
+pub fn split(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    // an argument was started, even if empty ("")
+    let mut started = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                if started {
+                    args.push(std::mem::take(&mut arg));
+                    started = false;
+                }
+            }
+            '\'' => {
+                started = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(format!("unterminated ' in: {}", line)),
+                    }
+                }
+            }
+            '"' => {
+                started = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('\n') => {}
+                            Some(c @ ('"' | '\\' | '$' | '`')) => arg.push(c),
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                            None => return Err(format!("unterminated \" in: {}", line)),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(format!("unterminated \" in: {}", line)),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some(c) => {
+                    started = true;
+                    arg.push(c);
+                }
+                None => return Err(format!("trailing \\ in: {}", line)),
+            },
+            c => {
+                started = true;
+                arg.push(c);
+            }
+        }
+    }
+    if started {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split;
+
+    fn ok(line: &str) -> Vec<String> {
+        split(line).unwrap()
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(
+            ok("  node  server.js\t--port 3000 "),
+            ["node", "server.js", "--port", "3000"]
+        );
+        assert!(ok("   ").is_empty());
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(
+            ok(r#"echo "arg 2" 'it''s' a"b"c"#),
+            ["echo", "arg 2", "its", "abc"]
+        );
+        assert_eq!(
+            ok(r#"sh -c 'echo "$HOME"'"#),
+            ["sh", "-c", r#"echo "$HOME""#]
+        );
+        assert_eq!(ok(r#"a "" ''"#), ["a", "", ""]);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            ok(r#"a\ b "c\"d" "e\nf" 'g\h'"#),
+            ["a b", "c\"d", "e\\nf", "g\\h"]
+        );
+    }
+
+    #[test]
+    fn unterminated() {
+        assert!(split("echo 'a").is_err());
+        assert!(split("echo \"a").is_err());
+        assert!(split("echo a\\").is_err());
+    }
+}
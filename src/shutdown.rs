@@ -0,0 +1,64 @@
+use log::info;
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+// Shutdown of gasket, requested by a signal or by a process that can't be restarted anymore.
+// main waits for the request, stops the servers and the supervised processes, then exits with
+// the requested exit code or, when none was given, with the exit code of the proxied process.
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+    receiver: watch::Receiver<bool>,
+    exit_code: Mutex<Option<i32>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            sender,
+            receiver,
+            exit_code: Mutex::new(None),
+        }
+    }
+
+    // the first exit code set wins
+    pub fn request(&self, exit_code: Option<i32>) {
+        if let Some(code) = exit_code {
+            self.set_exit_code(code);
+        }
+        if !self.is_requested() {
+            info!("Shutdown requested");
+            let _ = self.sender.send(true);
+        }
+    }
+
+    pub fn set_exit_code(&self, code: i32) {
+        let mut exit_code = self.exit_code.lock().unwrap();
+        if exit_code.is_none() {
+            *exit_code = Some(code);
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        *self.exit_code.lock().unwrap()
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
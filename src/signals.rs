@@ -0,0 +1,242 @@
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::str::FromStr;
+
+use signal_hook::consts::signal::*;
+
+// Signal forwarding: every catchable signal gasket receives is forwarded to the supervised
+// processes, except SIGTERM, SIGINT (Ctrl-C, docker run -it) and SIGQUIT which start the
+// graceful shutdown and are not forwarded (the processes then get --stop-signal). Signals can
+// be rewritten (TERM:QUIT for nginx-like apps, FROM:0 drops a signal), the rewrite applies to
+// the stop signal too. Signals reach:
+// - child: the supervised process only
+// - group (default): its process group, the process is started as the leader of a new group
+// - session: every process of its session, the process is started in a new session
+// SIGCHLD is handled by the reaper, fatal signals (SIGSEGV, SIGBUS...) are not caught.
+// SIGHUP can instead trigger a rolling restart of the proxied process, or reload gasket's TLS
+// certificates and keys (--sighup).
+pub const FORWARDED: &[libc::c_int] = &[
+    SIGHUP,
+    SIGUSR1,
+    SIGUSR2,
+    SIGWINCH,
+    SIGALRM,
+    SIGCONT,
+    SIGTSTP,
+    SIGTTIN,
+    SIGTTOU,
+    SIGURG,
+    SIGIO,
+    libc::SIGPWR,
+    SIGXCPU,
+    SIGXFSZ,
+    SIGVTALRM,
+    SIGPROF,
+];
+
+// a signal given by name (SIGTERM, TERM) or number
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Signal(pub libc::c_int);
+
+// FROM:TO, TO 0 drops the signal
+#[derive(Clone, Copy, Debug)]
+pub struct SignalRewrite {
+    pub from: Signal,
+    pub to: Option<Signal>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SignalTarget {
+    Child,
+    Group,
+    Session,
+}
+
+// what SIGHUP does
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SighupAction {
+    Forward,
+    Restart,
+    Reload,
+}
+
+#[derive(Clone, Debug)]
+pub struct SignalSettings {
+    pub rewrites: Vec<SignalRewrite>,
+    pub target: SignalTarget,
+    pub sighup: SighupAction,
+    // stops the processes, rewritten like forwarded signals
+    pub stop_signal: Signal,
+    pub stop_grace_period: chrono::Duration,
+}
+
+impl FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(signal) = s.parse::<libc::c_int>() {
+            if signal_hook::low_level::signal_name(signal).is_some() {
+                return Ok(Signal(signal));
+            }
+        }
+        let name = s.to_uppercase();
+        let name = if name.starts_with("SIG") {
+            name
+        } else {
+            format!("SIG{}", name)
+        };
+        (1..32)
+            .find(|signal| signal_hook::low_level::signal_name(*signal) == Some(name.as_str()))
+            .map(Signal)
+            .ok_or_else(|| format!("unknown signal: {}", s))
+    }
+}
+
+impl Signal {
+    pub fn name(&self) -> String {
+        signal_hook::low_level::signal_name(self.0)
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| self.0.to_string())
+    }
+}
+
+impl FromStr for SignalRewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once(':')
+            .ok_or_else(|| format!("signal rewrite {}: expected FROM:TO", s))?;
+        Ok(SignalRewrite {
+            from: from.parse()?,
+            to: match to {
+                "0" => None,
+                to => Some(to.parse()?),
+            },
+        })
+    }
+}
+
+impl FromStr for SignalTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "child" => Ok(SignalTarget::Child),
+            "group" => Ok(SignalTarget::Group),
+            "session" => Ok(SignalTarget::Session),
+            _ => Err(format!("unknown signal target: {}", s)),
+        }
+    }
+}
+
+impl FromStr for SighupAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forward" => Ok(SighupAction::Forward),
+            "restart" => Ok(SighupAction::Restart),
+            "reload" => Ok(SighupAction::Reload),
+            _ => Err(format!("unknown SIGHUP action: {}", s)),
+        }
+    }
+}
+
+impl SignalSettings {
+    // the signal actually sent for a signal received, None when it is dropped
+    pub fn rewrite(&self, signal: Signal) -> Option<Signal> {
+        match self.rewrites.iter().find(|r| r.from == signal) {
+            Some(rewrite) => rewrite.to,
+            None => Some(signal),
+        }
+    }
+
+    // starts the process in its own group or session
+    pub fn configure(&self, command: &mut Command) {
+        match self.target {
+            SignalTarget::Child => {}
+            SignalTarget::Group => {
+                command.process_group(0);
+            }
+            SignalTarget::Session => unsafe {
+                command.pre_exec(|| {
+                    if libc::setsid() == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            },
+        }
+    }
+
+    // processes of the group or session of a supervised process
+    pub fn members(&self, pid: libc::pid_t) -> Vec<libc::pid_t> {
+        match self.target {
+            SignalTarget::Child => Vec::new(),
+            SignalTarget::Group => crate::process_tree::group_members(pid),
+            SignalTarget::Session => crate::process_tree::session_members(pid),
+        }
+    }
+
+    // sends a signal to a supervised process, its group or its session
+    pub fn send(&self, pid: libc::pid_t, signal: libc::c_int) {
+        match self.target {
+            SignalTarget::Child => unsafe {
+                libc::kill(pid, signal);
+            },
+            SignalTarget::Group => unsafe {
+                libc::kill(-pid, signal);
+            },
+            SignalTarget::Session => {
+                for member in crate::process_tree::session_members(pid) {
+                    unsafe { libc::kill(member, signal) };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals() {
+        assert_eq!("TERM".parse::<Signal>(), Ok(Signal(SIGTERM)));
+        assert_eq!("SIGTERM".parse::<Signal>(), Ok(Signal(SIGTERM)));
+        assert_eq!("sigterm".parse::<Signal>(), Ok(Signal(SIGTERM)));
+        assert_eq!("15".parse::<Signal>(), Ok(Signal(SIGTERM)));
+        assert_eq!(Signal(SIGQUIT).name(), "SIGQUIT");
+        assert!("NOPE".parse::<Signal>().is_err());
+        assert!("0".parse::<Signal>().is_err());
+    }
+
+    #[test]
+    fn rewrites() {
+        let settings = SignalSettings {
+            rewrites: vec!["TERM:QUIT".parse().unwrap(), "USR1:0".parse().unwrap()],
+            target: SignalTarget::Group,
+            sighup: SighupAction::Forward,
+            stop_signal: Signal(SIGTERM),
+            stop_grace_period: chrono::Duration::seconds(10),
+        };
+        assert_eq!(settings.rewrite(Signal(SIGTERM)), Some(Signal(SIGQUIT)));
+        assert_eq!(settings.rewrite(Signal(SIGUSR1)), None);
+        assert_eq!(settings.rewrite(Signal(SIGUSR2)), Some(Signal(SIGUSR2)));
+        assert!("TERM".parse::<SignalRewrite>().is_err());
+        assert!("TERM:NOPE".parse::<SignalRewrite>().is_err());
+    }
+
+    #[test]
+    fn targets_and_sighup_actions() {
+        assert_eq!("child".parse(), Ok(SignalTarget::Child));
+        assert_eq!("group".parse(), Ok(SignalTarget::Group));
+        assert_eq!("session".parse(), Ok(SignalTarget::Session));
+        assert!("process".parse::<SignalTarget>().is_err());
+        assert_eq!("forward".parse(), Ok(SighupAction::Forward));
+        assert_eq!("restart".parse(), Ok(SighupAction::Restart));
+        assert_eq!("reload".parse(), Ok(SighupAction::Reload));
+        assert!("stop".parse::<SighupAction>().is_err());
+    }
+}
@@ -1,223 +1,918 @@
+use crate::sharded_map::ShardedMap;
 use chrono::{DateTime, Duration, Local};
 use log::info;
-use std::collections::HashMap;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
 
 // Stability patterns:
 // Throttling: Ensure only max_requests can happen on a given timewindow
 // Circuit Breaker: once max_tries with errors are reached, trip and interrupt the circuit. it can be reset
-// Exponential Backoff: exponentially increses Timeout for each retry
+// Exponential Backoff: exponentially increases the delay between retries, with jitter
+// Retry Budget: ensure retries stay under a percentage of the live traffic for a key
+// Concurrency Limiter: caps in-flight upstream requests, queues a bounded number of waiters and
+// sheds the rest. The limit is either fixed or adapted from the observed latency (AIMD, gradient)
 
 pub struct CircuitBreaker {
     error_count: u16,
     max_trips: u16,
     last_error: DateTime<Local>,
-    created_at: DateTime<Local>,
+    reset_timeout: Duration,
+}
+
+// Circuit breaker state as reported by the metrics
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    Closed = 0,
+    Open = 1,
+    HalfOpen = 2,
+}
+
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerSettings {
+    pub max_trips: u16,
+    // an open circuit lets requests through again (half open) after reset_timeout
+    pub reset_timeout: Duration,
+}
+
+// Throttling algorithms:
+// Token bucket: the bucket holds up to max_requests tokens and is refilled continuously at
+// max_requests per time_window, allowing short bursts up to the bucket size
+// Sliding window: counts requests on the current and previous fixed windows and weights the
+// previous one by how much of it still overlaps the sliding window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThrottlingAlgorithm {
+    TokenBucket,
+    SlidingWindow,
+}
+
+// What a throttler is keyed by: each distinct key gets its own throttler
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThrottlingKey {
+    Route,
+    ClientIp,
+    Identity,
+    Header,
+}
+
+#[derive(Clone, Debug)]
+pub struct ThrottlerSettings {
+    pub algorithm: ThrottlingAlgorithm,
+    pub key: ThrottlingKey,
+    pub header: String,
+    pub max_requests: u32,
+    pub time_window: Duration,
+}
+
+// Outcome of a throttler check, carries what is needed to build the RateLimit-* headers
+#[derive(Clone, Copy, Debug)]
+pub struct ThrottleDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: Duration,
+    pub retry_after: Duration,
 }
 
 pub struct Throttler {
-    max_requests: i32,
-    current_requests: i32,
-    last_request: DateTime<Local>,
+    algorithm: ThrottlingAlgorithm,
+    max_requests: u32,
     time_window: Duration,
+    // token bucket state
+    tokens: f64,
+    last_refill: DateTime<Local>,
+    // sliding window state
+    window_start: DateTime<Local>,
+    current_requests: u32,
+    previous_requests: u32,
 }
 
+// Jitter applied to the exponential backoff:
+// Full: sleep a random amount between zero and the exponential delay
+// Decorrelated: sleep a random amount between the base delay and three times the previous sleep
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Jitter {
+    Full,
+    Decorrelated,
+}
+
+#[derive(Clone, Debug)]
+pub struct RetrySettings {
+    pub methods: Vec<String>,
+    pub status_codes: Vec<u16>,
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: Jitter,
+    pub deadline: Duration,
+    pub budget_ratio: f64,
+    pub budget_min_per_second: u32,
+}
+
+// Delays between the attempts of a single request
 #[derive(Clone, Copy)]
 pub struct ExponentialBackoff {
-    current_timeout: Duration,
-    requests: i32,
-    max_timeout: Duration,
-    max_requests: i32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: Jitter,
+    attempts: i32,
+    previous_delay: Duration,
+}
+
+// Retries allowed for a key are budget_min_per_second per second plus budget_ratio of the
+// requests seen on the budget window
+pub struct RetryBudget {
+    ratio: f64,
+    min_per_second: u32,
+    time_window: Duration,
+    window_start: DateTime<Local>,
+    requests: u32,
+    retries: u32,
+}
+
+// How the concurrency limit evolves:
+// Fixed: the configured limit is never changed
+// Aimd: additive increase while requests succeed, multiplicative decrease on drops
+// Gradient: the limit follows the ratio between the long term and the recent latency
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConcurrencyMode {
+    Fixed,
+    Aimd,
+    Gradient,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConcurrencySettings {
+    pub mode: ConcurrencyMode,
+    pub initial_limit: u32,
+    pub min_limit: u32,
+    pub max_limit: u32,
+    pub max_queue: u32,
+    pub queue_timeout: Duration,
+}
+
+// Outcome of an upstream request as seen by the concurrency limiter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConcurrencyOutcome {
+    // upstream answered, latency is sampled
+    Success,
+    // upstream timed out or shed the request itself
+    Dropped,
+    // outcome says nothing about upstream capacity (e.g. connect error)
+    Ignore,
+}
+
+struct LimiterState {
+    limit: f64,
+    in_flight: u32,
+    // requests waiting for a slot, oldest first
+    waiters: VecDeque<Waiter>,
+    next_waiter: u64,
+    // gradient: exponentially smoothed long term rtt in milliseconds
+    long_rtt: f64,
+}
+
+struct Waiter {
+    id: u64,
+    granted: oneshot::Sender<()>,
+}
+
+pub struct ConcurrencyLimiter {
+    settings: ConcurrencySettings,
+    state: Mutex<LimiterState>,
+}
+
+// In-flight slot, must be released with the request outcome
+pub struct ConcurrencyPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+    started: DateTime<Local>,
+    released: bool,
+}
+
+// Queue slot of a request waiting for a permit, freed however the wait ends (permit, timeout
+// or the request future dropped)
+struct QueueSlot<'a> {
+    limiter: &'a ConcurrencyLimiter,
+    id: u64,
+    granted: oneshot::Receiver<()>,
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if let Some(index) = state.waiters.iter().position(|w| w.id == self.id) {
+            state.waiters.remove(index);
+        } else if self.granted.try_recv().is_ok() {
+            // handed a slot after giving up on the wait, pass it on to the next waiter
+            state.in_flight -= 1;
+            self.limiter.grant(&mut state);
+        }
+    }
 }
 
 pub struct StabilityPatterns {
-    pub circuitbreakers: Arc<Mutex<HashMap<String, CircuitBreaker>>>,
-    pub throttlers: Arc<Mutex<HashMap<String, Throttler>>>,
-    pub backoffs: Arc<Mutex<HashMap<String, ExponentialBackoff>>>,
+    pub circuitbreakers: ShardedMap<CircuitBreaker>,
+    pub throttlers: ShardedMap<Throttler>,
+    pub retry_budgets: ShardedMap<RetryBudget>,
+    pub circuitbreaker_settings: Option<CircuitBreakerSettings>,
+    pub throttler_settings: Option<ThrottlerSettings>,
+    pub retry_settings: Option<RetrySettings>,
+    pub concurrency_limiter: Option<Arc<ConcurrencyLimiter>>,
+    // upstream timeout for each attempt
+    pub timeout: Duration,
+    // per key state unused for this long is evicted
+    pub idle_timeout: Duration,
+}
+
+impl FromStr for ConcurrencyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(ConcurrencyMode::Fixed),
+            "aimd" => Ok(ConcurrencyMode::Aimd),
+            "gradient" => Ok(ConcurrencyMode::Gradient),
+            _ => Err(format!("unknown concurrency mode: {}", s)),
+        }
+    }
+}
+
+impl FromStr for ThrottlingAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "token-bucket" => Ok(ThrottlingAlgorithm::TokenBucket),
+            "sliding-window" => Ok(ThrottlingAlgorithm::SlidingWindow),
+            _ => Err(format!("unknown throttling algorithm: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Jitter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Jitter::Full),
+            "decorrelated" => Ok(Jitter::Decorrelated),
+            _ => Err(format!("unknown jitter: {}", s)),
+        }
+    }
+}
+
+impl FromStr for ThrottlingKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "route" => Ok(ThrottlingKey::Route),
+            "ip" => Ok(ThrottlingKey::ClientIp),
+            "identity" => Ok(ThrottlingKey::Identity),
+            "header" => Ok(ThrottlingKey::Header),
+            _ => Err(format!("unknown throttling key: {}", s)),
+        }
+    }
 }
 
 impl CircuitBreaker {
-    fn new(max_trips: u16) -> Self {
-        let s = Self {
+    fn new(max_trips: u16, reset_timeout: Duration) -> Self {
+        Self {
             max_trips,
             error_count: 0,
             last_error: Local::now(),
-            created_at: Local::now(),
-        };
-        return s;
+            reset_timeout,
+        }
     }
 
     fn trip(&mut self) -> bool {
-        self.error_count += 1;
+        self.error_count = self.error_count.saturating_add(1);
         self.last_error = Local::now();
-        if self.error_count > self.max_trips {
-            return false;
-        }
-        return true;
+        self.error_count <= self.max_trips
+    }
+
+    // closed, or open for longer than reset_timeout (half open: a failure trips it again)
+    fn allow(&self) -> bool {
+        self.error_count <= self.max_trips || Local::now() - self.last_error >= self.reset_timeout
     }
 
     fn reset(&mut self) {
         self.error_count = 0;
     }
+
+    fn state(&self) -> CircuitState {
+        if self.error_count <= self.max_trips {
+            CircuitState::Closed
+        } else if self.allow() {
+            CircuitState::HalfOpen
+        } else {
+            CircuitState::Open
+        }
+    }
 }
 
 impl Throttler {
-    fn new(limit: i32, time_window: Duration) -> Self {
+    fn new(algorithm: ThrottlingAlgorithm, limit: u32, time_window: Duration) -> Self {
+        let now = Local::now();
         Self {
+            algorithm,
             max_requests: limit,
-            current_requests: 0,
-            last_request: Local::now(),
             time_window,
+            tokens: limit as f64,
+            last_refill: now,
+            window_start: now,
+            current_requests: 0,
+            previous_requests: 0,
         }
     }
-    fn check(&mut self) -> bool {
-        if (self.last_request - Local::now()) < self.time_window {
-            self.current_requests += 1;
-            if self.current_requests > self.max_requests {
-                return false;
-            }
+
+    fn check(&mut self) -> ThrottleDecision {
+        match self.algorithm {
+            ThrottlingAlgorithm::TokenBucket => self.check_token_bucket(Local::now()),
+            ThrottlingAlgorithm::SlidingWindow => self.check_sliding_window(Local::now()),
+        }
+    }
+
+    fn window_ms(&self) -> f64 {
+        std::cmp::max(self.time_window.num_milliseconds(), 1) as f64
+    }
+
+    fn check_token_bucket(&mut self, now: DateTime<Local>) -> ThrottleDecision {
+        let capacity = self.max_requests as f64;
+        // tokens per millisecond
+        let rate = capacity / self.window_ms();
+
+        let elapsed = (now - self.last_refill).num_milliseconds().max(0) as f64;
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.last_refill = now;
+
+        let allowed = self.tokens >= 1.0;
+        if allowed {
+            self.tokens -= 1.0;
+        }
+
+        // time until the bucket is full again and until the next token is available
+        let reset = Duration::milliseconds(((capacity - self.tokens) / rate).ceil() as i64);
+        let retry_after = if allowed {
+            Duration::zero()
         } else {
-            self.current_requests = 1;
+            Duration::milliseconds(((1.0 - self.tokens) / rate).ceil() as i64)
+        };
+
+        ThrottleDecision {
+            allowed,
+            limit: self.max_requests,
+            remaining: self.tokens.floor() as u32,
+            reset,
+            retry_after,
+        }
+    }
+
+    fn check_sliding_window(&mut self, now: DateTime<Local>) -> ThrottleDecision {
+        // roll the fixed windows forward, a gap longer than one window clears both
+        let mut elapsed = now - self.window_start;
+        if elapsed >= self.time_window + self.time_window {
+            self.previous_requests = 0;
+            self.current_requests = 0;
+            self.window_start = now;
+            elapsed = Duration::zero();
+        } else if elapsed >= self.time_window {
+            self.previous_requests = self.current_requests;
+            self.current_requests = 0;
+            self.window_start = self.window_start + self.time_window;
+            elapsed = now - self.window_start;
+        }
+
+        let overlap = 1.0 - (elapsed.num_milliseconds() as f64 / self.window_ms());
+        let weighted = self.previous_requests as f64 * overlap + self.current_requests as f64;
+
+        let allowed = weighted + 1.0 <= self.max_requests as f64;
+        if allowed {
+            self.current_requests += 1;
+        }
+
+        let used = if allowed { weighted + 1.0 } else { weighted };
+        let reset = self.time_window - elapsed;
+        ThrottleDecision {
+            allowed,
+            limit: self.max_requests,
+            remaining: (self.max_requests as f64 - used).max(0.0).floor() as u32,
+            reset,
+            retry_after: if allowed { Duration::zero() } else { reset },
         }
-        return true;
     }
 }
 
 impl ExponentialBackoff {
-    fn new() -> Self {
+    pub fn new(base_delay: Duration, max_delay: Duration, jitter: Jitter) -> Self {
+        Self {
+            base_delay,
+            max_delay,
+            jitter,
+            attempts: 0,
+            previous_delay: base_delay,
+        }
+    }
+
+    pub fn next(&mut self) -> Duration {
+        let base = self.base_delay.num_milliseconds().max(1);
+        let max = self.max_delay.num_milliseconds().max(base);
+        let mut rng = rand::thread_rng();
+
+        let delay = match self.jitter {
+            Jitter::Full => {
+                // cap to max_delay, also keeps the power from overflowing
+                let exp = 2.0f64.powi(self.attempts.min(32)) * base as f64;
+                let ceiling = (exp as i64).min(max);
+                rng.gen_range(0..=ceiling)
+            }
+            Jitter::Decorrelated => {
+                let upper = (self.previous_delay.num_milliseconds() * 3).clamp(base, max);
+                rng.gen_range(base..=upper)
+            }
+        };
+
+        self.attempts += 1;
+        self.previous_delay = Duration::milliseconds(delay);
+        self.previous_delay
+    }
+}
+
+impl RetryBudget {
+    fn new(ratio: f64, min_per_second: u32) -> Self {
         Self {
-            current_timeout: Duration::milliseconds(100),
+            ratio,
+            min_per_second,
+            time_window: Duration::seconds(10),
+            window_start: Local::now(),
             requests: 0,
-            max_timeout: Duration::seconds(60), // hardcoded 60s ceiling
-            max_requests: 50, // arbitrary upper limit before resetting (or failing for good)
+            retries: 0,
         }
     }
 
-    fn current(&mut self) -> Duration {
-        self.current_timeout
+    fn roll(&mut self) {
+        let now = Local::now();
+        if now - self.window_start >= self.time_window {
+            self.window_start = now;
+            self.requests = 0;
+            self.retries = 0;
+        }
     }
 
-    fn next(&mut self) -> Duration {
-        let base = 2.0f64;
-        if self.requests == 0 {
-            self.current_timeout = Duration::milliseconds(100);
-        } else {
-            // cap to max_timeout
-            let to = self.current_timeout
-                + Duration::milliseconds((base.powi(self.requests) * 10.0) as i64);
-
-            if to > self.max_timeout {
-                self.current_timeout = self.max_timeout
-            } else {
-                self.current_timeout = to
+    fn deposit(&mut self) {
+        self.roll();
+        self.requests += 1;
+    }
+
+    fn withdraw(&mut self) -> bool {
+        self.roll();
+        let allowed = self.min_per_second as f64 * self.time_window.num_seconds() as f64
+            + self.ratio * self.requests as f64;
+        if (self.retries + 1) as f64 > allowed {
+            return false;
+        }
+        self.retries += 1;
+        true
+    }
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(settings: ConcurrencySettings) -> Self {
+        let limit = settings.initial_limit.max(1) as f64;
+        Self {
+            settings,
+            state: Mutex::new(LimiterState {
+                limit,
+                in_flight: 0,
+                waiters: VecDeque::new(),
+                next_waiter: 0,
+                long_rtt: 0.0,
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.state.lock().unwrap().limit as u32
+    }
+
+    // waits for an in-flight slot, None means the request must be shed. Waiters get freed slots
+    // in arrival order and new requests queue behind them.
+    pub async fn acquire(self: &Arc<Self>) -> Option<ConcurrencyPermit> {
+        let mut slot = {
+            let mut state = self.state.lock().unwrap();
+            if state.waiters.is_empty() && (state.in_flight as f64) < state.limit.floor() {
+                state.in_flight += 1;
+                return Some(self.permit());
+            }
+            if state.waiters.len() >= self.settings.max_queue as usize {
+                return None;
             }
+            let (granted, receiver) = oneshot::channel();
+            let id = state.next_waiter;
+            state.next_waiter += 1;
+            state.waiters.push_back(Waiter { id, granted });
+            QueueSlot {
+                limiter: self,
+                id,
+                granted: receiver,
+            }
+        };
+
+        let wait = self.settings.queue_timeout.to_std().unwrap_or_default();
+        match actix_web::rt::time::timeout(wait, &mut slot.granted).await {
+            // the slot was counted as in flight by grant
+            Ok(Ok(())) => Some(self.permit()),
+            _ => None,
         }
-        self.requests += 1;
-        self.current_timeout
     }
-    // next but resets after max_requests
-    // be careful
-    fn next_with_reset(&mut self) -> Duration {
-        let d = self.next();
-        if self.requests > self.max_requests {
-            self.reset()
+
+    fn permit(self: &Arc<Self>) -> ConcurrencyPermit {
+        ConcurrencyPermit {
+            limiter: self.clone(),
+            started: Local::now(),
+            released: false,
         }
-        d
     }
 
-    fn reset(&mut self) {
-        self.requests = 0;
-        self.current_timeout = Duration::milliseconds(100);
+    // hands free slots to the oldest waiters
+    fn grant(&self, state: &mut LimiterState) {
+        while (state.in_flight as f64) < state.limit.floor() {
+            match state.waiters.pop_front() {
+                Some(waiter) => {
+                    if waiter.granted.send(()).is_ok() {
+                        state.in_flight += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn release(&self, outcome: ConcurrencyOutcome, rtt: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let in_flight = state.in_flight;
+        state.in_flight -= 1;
+        self.update_limit(&mut state, outcome, rtt, in_flight);
+        self.grant(&mut state);
+    }
+
+    fn update_limit(
+        &self,
+        state: &mut LimiterState,
+        outcome: ConcurrencyOutcome,
+        rtt: Duration,
+        in_flight: u32,
+    ) {
+        let min = self.settings.min_limit.max(1) as f64;
+        let max = self.settings.max_limit.max(self.settings.min_limit.max(1)) as f64;
+        let limit = match (self.settings.mode, outcome) {
+            (ConcurrencyMode::Fixed, _) | (_, ConcurrencyOutcome::Ignore) => return,
+            (ConcurrencyMode::Aimd, ConcurrencyOutcome::Dropped) => state.limit * 0.9,
+            (ConcurrencyMode::Aimd, ConcurrencyOutcome::Success) => {
+                // only grow when the limit is actually being used
+                if in_flight as f64 * 2.0 >= state.limit {
+                    state.limit + 1.0
+                } else {
+                    state.limit
+                }
+            }
+            (ConcurrencyMode::Gradient, ConcurrencyOutcome::Dropped) => state.limit * 0.9,
+            (ConcurrencyMode::Gradient, ConcurrencyOutcome::Success) => {
+                let sample = rtt.num_microseconds().unwrap_or(i64::MAX) as f64 / 1000.0;
+                let sample = sample.max(0.001);
+                if state.long_rtt == 0.0 {
+                    state.long_rtt = sample;
+                } else {
+                    state.long_rtt = state.long_rtt * 0.95 + sample * 0.05;
+                }
+                // latency above the long term average shrinks the limit, queue_size headroom
+                // lets it grow while latency is stable
+                let gradient = (state.long_rtt / sample).clamp(0.5, 1.0);
+                let queue_size = state.limit.sqrt();
+                let new_limit = state.limit * gradient + queue_size;
+                state.limit * 0.8 + new_limit * 0.2
+            }
+        };
+        state.limit = limit.clamp(min, max);
+    }
+}
+
+impl ConcurrencyPermit {
+    pub fn release(mut self, outcome: ConcurrencyOutcome) {
+        self.released = true;
+        let rtt = Local::now() - self.started;
+        self.limiter.release(outcome, rtt);
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        // permits dropped without an outcome (e.g. cancelled requests) still free the slot
+        if !self.released {
+            self.released = true;
+            self.limiter
+                .release(ConcurrencyOutcome::Ignore, Duration::zero());
+        }
     }
 }
 
 impl StabilityPatterns {
-    pub fn new() -> Self {
-        let s = Self {
-            circuitbreakers: Arc::new(Mutex::new(HashMap::new())),
-            throttlers: Arc::new(Mutex::new(HashMap::new())),
-            backoffs: Arc::new(Mutex::new(HashMap::new())),
-        };
-        return s;
+    pub fn new(
+        circuitbreaker_settings: Option<CircuitBreakerSettings>,
+        throttler_settings: Option<ThrottlerSettings>,
+        retry_settings: Option<RetrySettings>,
+        concurrency_settings: Option<ConcurrencySettings>,
+        timeout: Duration,
+        max_keys: usize,
+        idle_timeout: Duration,
+    ) -> Self {
+        Self {
+            circuitbreakers: ShardedMap::new(max_keys),
+            throttlers: ShardedMap::new(max_keys),
+            retry_budgets: ShardedMap::new(max_keys),
+            circuitbreaker_settings,
+            throttler_settings,
+            retry_settings,
+            concurrency_limiter: concurrency_settings
+                .map(|settings| Arc::new(ConcurrencyLimiter::new(settings))),
+            timeout,
+            idle_timeout,
+        }
     }
 
-    pub fn circuitbreaker(&mut self, name: String, max_trips: u16) {
-        let cb = CircuitBreaker::new(max_trips);
-        self.circuitbreakers.lock().unwrap().insert(name, cb);
+    // state of the circuit breaker for name, None when circuit breakers are disabled
+    pub fn circuit_state(&self, name: &str) -> Option<CircuitState> {
+        self.circuitbreaker_settings.as_ref()?;
+        Some(
+            self.circuitbreakers
+                .get_with(name, |cb| cb.state())
+                .unwrap_or(CircuitState::Closed),
+        )
     }
 
-    pub fn trip(&mut self, name: String) -> bool {
+    // true if requests for name can go through, unknown circuit breakers are closed
+    pub fn circuit_closed(&self, name: String) -> bool {
+        if self.circuitbreaker_settings.is_none() {
+            return true;
+        }
         self.circuitbreakers
-            .lock()
-            .unwrap()
-            .get_mut(&name)
-            .unwrap()
-            .trip()
+            .get_with(&name, |cb| cb.allow())
+            .unwrap_or(true)
     }
 
-    pub fn reset(&mut self, name: String) {
-        self.circuitbreakers
-            .lock()
-            .unwrap()
-            .get_mut(&name)
-            .unwrap()
-            .reset()
+    // records an upstream failure for name, returns false once the circuit is open
+    pub fn trip(&self, name: String) -> bool {
+        let settings = match self.circuitbreaker_settings.as_ref() {
+            Some(s) => s,
+            None => return true,
+        };
+        let closed = self.circuitbreakers.with(
+            &name,
+            || CircuitBreaker::new(settings.max_trips, settings.reset_timeout),
+            |cb| cb.trip(),
+        );
+        if !closed {
+            info!("circuit open for {}", name);
+        }
+        closed
     }
 
-    pub fn throttler(&mut self, name: String, limit: i32, time_window: Duration) {
-        let tt = Throttler::new(limit, time_window);
-        self.throttlers.lock().unwrap().insert(name, tt);
+    // records an upstream success for name
+    pub fn reset(&self, name: String) {
+        self.circuitbreakers.get_with(&name, |cb| cb.reset());
     }
 
-    pub fn throttle(&mut self, name: String) -> bool {
-        self.throttlers
-            .lock()
-            .unwrap()
-            .get_mut(&name)
-            .unwrap()
-            .check()
+    // checks the throttler for name, creating it from throttler_settings on first use
+    // returns None when throttling is disabled
+    pub fn throttle(&self, name: String) -> Option<ThrottleDecision> {
+        let settings = self.throttler_settings.as_ref()?;
+        let decision = self.throttlers.with(
+            &name,
+            || {
+                Throttler::new(
+                    settings.algorithm,
+                    settings.max_requests,
+                    settings.time_window,
+                )
+            },
+            |tt| tt.check(),
+        );
+        Some(decision)
     }
 
-    pub fn exponential_backoff(&mut self, name: String) {
-        if !self.backoffs.lock().unwrap().contains_key(&name.clone()) {
-            let eb = ExponentialBackoff::new();
-            self.backoffs.lock().unwrap().insert(name, eb);
+    // backoff for a new request, None when retries are disabled
+    pub fn exponential_backoff(&self) -> Option<ExponentialBackoff> {
+        let settings = self.retry_settings.as_ref()?;
+        Some(ExponentialBackoff::new(
+            settings.base_delay,
+            settings.max_delay,
+            settings.jitter,
+        ))
+    }
+
+    // accounts a live request against the retry budget of name
+    pub fn deposit_retry_budget(&self, name: String) {
+        let settings = match self.retry_settings.as_ref() {
+            Some(s) => s,
+            None => return,
+        };
+        self.retry_budgets.with(
+            &name,
+            || RetryBudget::new(settings.budget_ratio, settings.budget_min_per_second),
+            |budget| budget.deposit(),
+        );
+    }
+
+    // true if the retry budget of name still allows a retry
+    pub fn withdraw_retry_budget(&self, name: String) -> bool {
+        let allowed = self
+            .retry_budgets
+            .get_with(&name, |budget| budget.withdraw())
+            .unwrap_or(false);
+        if !allowed {
+            info!("retry budget exhausted for {}", name);
         }
+        allowed
     }
 
-    pub fn next_backoff(&mut self, name: String) -> Duration {
-        info!("backing off");
-        match self.backoffs.lock().unwrap().get_mut(&name.clone()) {
-            Some(q) => {
-                let n = q.next();
-                info!("backing off for {}: {} - {:?}", name.clone(), q.requests, n);
-                n
-            }
-            None => {
-                info!(
-                    "backing off for {} with 5s Duration (bypassing backoff)",
-                    name.clone(),
-                );
-                Duration::seconds(5) // default http client timeout
-            }
+    // state of every known circuit breaker, by key
+    pub fn circuit_states(&self) -> Vec<(String, CircuitState)> {
+        self.circuitbreakers.snapshot(|cb| cb.state())
+    }
+
+    // drops per key state not used for idle_timeout, idle throttlers and budgets are
+    // back to their initial state anyway so this only reclaims memory
+    pub fn evict_idle(&self) {
+        let evicted = self.circuitbreakers.evict_idle(self.idle_timeout)
+            + self.throttlers.evict_idle(self.idle_timeout)
+            + self.retry_budgets.evict_idle(self.idle_timeout);
+        if evicted > 0 {
+            info!("evicted {} idle stability pattern entries", evicted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn limiter(limit: u32, max_queue: u32) -> Arc<ConcurrencyLimiter> {
+        Arc::new(ConcurrencyLimiter::new(ConcurrencySettings {
+            mode: ConcurrencyMode::Fixed,
+            initial_limit: limit,
+            min_limit: 1,
+            max_limit: limit,
+            max_queue,
+            queue_timeout: Duration::seconds(10),
+        }))
+    }
+
+    // a throttler whose state starts at t0
+    fn throttler(
+        algorithm: ThrottlingAlgorithm,
+        limit: u32,
+        window_ms: i64,
+        t0: DateTime<Local>,
+    ) -> Throttler {
+        let mut throttler = Throttler::new(algorithm, limit, Duration::milliseconds(window_ms));
+        throttler.last_refill = t0;
+        throttler.window_start = t0;
+        throttler
+    }
+
+    fn at(t0: DateTime<Local>, ms: i64) -> DateTime<Local> {
+        t0 + Duration::milliseconds(ms)
+    }
+
+    #[test]
+    fn token_bucket_burst_and_refill() {
+        let t0 = Local::now();
+        // 8 tokens over 1024ms: one token every 128ms
+        let mut throttler = throttler(ThrottlingAlgorithm::TokenBucket, 8, 1024, t0);
+        for remaining in (0..8).rev() {
+            let decision = throttler.check_token_bucket(t0);
+            assert!(decision.allowed);
+            assert_eq!(decision.limit, 8);
+            assert_eq!(decision.remaining, remaining);
+            assert_eq!(decision.retry_after, Duration::zero());
         }
+        let decision = throttler.check_token_bucket(t0);
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.reset, Duration::milliseconds(1024));
+        assert_eq!(decision.retry_after, Duration::milliseconds(128));
+
+        // 320ms later 2.5 tokens are back
+        let decision = throttler.check_token_bucket(at(t0, 320));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 1);
+        assert_eq!(decision.reset, Duration::milliseconds(832));
+
+        // the bucket never holds more than its capacity
+        let decision = throttler.check_token_bucket(at(t0, 60_000));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 7);
     }
 
-    pub fn current_timeout(&mut self, name: String) -> Duration {
-        self.backoffs
-            .lock()
-            .unwrap()
-            .get_mut(&name)
-            .unwrap()
-            .current()
+    #[test]
+    fn sliding_window_roll_over() {
+        let t0 = Local::now();
+        let mut throttler = throttler(ThrottlingAlgorithm::SlidingWindow, 4, 1000, t0);
+        for remaining in (0..4).rev() {
+            let decision = throttler.check_sliding_window(t0);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let decision = throttler.check_sliding_window(at(t0, 100));
+        assert!(!decision.allowed);
+        assert_eq!(decision.reset, Duration::milliseconds(900));
+        assert_eq!(decision.retry_after, Duration::milliseconds(900));
+
+        // half way through the next window half of the previous one still counts
+        let decision = throttler.check_sliding_window(at(t0, 1500));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 1);
+        assert_eq!(decision.reset, Duration::milliseconds(500));
+        assert!(throttler.check_sliding_window(at(t0, 1500)).allowed);
+        let decision = throttler.check_sliding_window(at(t0, 1500));
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.retry_after, Duration::milliseconds(500));
+    }
+
+    #[test]
+    fn sliding_window_reset_after_two_windows() {
+        let t0 = Local::now();
+        let mut throttler = throttler(ThrottlingAlgorithm::SlidingWindow, 4, 1000, t0);
+        for _ in 0..4 {
+            throttler.check_sliding_window(t0);
+        }
+        assert!(!throttler.check_sliding_window(t0).allowed);
+        // a gap of two windows forgets both
+        let decision = throttler.check_sliding_window(at(t0, 2000));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 3);
+        assert_eq!(decision.reset, Duration::milliseconds(1000));
+    }
+
+    fn queued(limiter: &ConcurrencyLimiter) -> u32 {
+        limiter.state.lock().unwrap().waiters.len() as u32
     }
 
-    pub fn reset_backoff(&mut self, name: String) {
-        self.backoffs
-            .lock()
-            .unwrap()
-            .get_mut(&name)
-            .unwrap()
-            .reset()
+    #[test]
+    fn dropped_waiters_free_their_queue_slot() {
+        actix_web::rt::System::new().block_on(async {
+            let limiter = limiter(1, 1);
+            let permit = limiter.acquire().await.unwrap();
+            let mut waiting = Box::pin(limiter.acquire());
+            assert!(waiting.as_mut().now_or_never().is_none());
+            assert_eq!(queued(&limiter), 1);
+            // the queue is full
+            assert!(limiter.acquire().await.is_none());
+            drop(waiting);
+            assert_eq!(queued(&limiter), 0);
+
+            let mut waiting = Box::pin(limiter.acquire());
+            assert!(waiting.as_mut().now_or_never().is_none());
+            permit.release(ConcurrencyOutcome::Success);
+            assert!(waiting.await.is_some());
+            assert_eq!(queued(&limiter), 0);
+        });
+    }
+
+    #[test]
+    fn waiters_are_served_in_arrival_order() {
+        actix_web::rt::System::new().block_on(async {
+            let limiter = limiter(1, 3);
+            let permit = limiter.acquire().await.unwrap();
+            let mut first = Box::pin(limiter.acquire());
+            let mut second = Box::pin(limiter.acquire());
+            let mut third = Box::pin(limiter.acquire());
+            assert!(first.as_mut().now_or_never().is_none());
+            assert!(second.as_mut().now_or_never().is_none());
+            assert!(third.as_mut().now_or_never().is_none());
+            // the queue is bounded at max_queue
+            assert_eq!(queued(&limiter), 3);
+            assert!(limiter.acquire().await.is_none());
+
+            // a released slot goes to the oldest waiter, not to a newer request
+            permit.release(ConcurrencyOutcome::Success);
+            assert!(limiter.acquire().now_or_never().is_none());
+            assert!(third.as_mut().now_or_never().is_none());
+            assert!(second.as_mut().now_or_never().is_none());
+            let permit = first.await.unwrap();
+
+            // a waiter that gives up after being granted passes the slot on
+            permit.release(ConcurrencyOutcome::Success);
+            drop(second);
+            let permit = third.await.unwrap();
+            assert_eq!(queued(&limiter), 0);
+            assert_eq!(limiter.state.lock().unwrap().in_flight, 1);
+            drop(permit);
+            assert_eq!(limiter.state.lock().unwrap().in_flight, 0);
+        });
     }
 }
@@ -0,0 +1,1111 @@
+use crate::routes::RouteKey;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage};
+use chrono::{DateTime, Duration, Local};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use log::info;
+use serde_json::json;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+// Tracing: a server span per request and a client span per upstream attempt, exported with
+// OTLP (http/json on <endpoint>/v1/traces or grpc on plaintext http/2) to a collector.
+// Incoming trace context is continued from the configured propagation formats, checked in
+// order, and every format is injected in the upstream requests:
+// - w3c: traceparent / tracestate
+// - b3: single b3 header
+// - b3multi: X-B3-TraceId, X-B3-SpanId, X-B3-ParentSpanId, X-B3-Sampled, X-B3-Flags
+// Requests without a sampling decision from upstream are sampled with sample_ratio.
+// Spans are batched by an exporter task, spans are dropped when the queue is full.
+const QUEUE_SIZE: usize = 2048;
+const BATCH_SIZE: usize = 512;
+const EXPORT_INTERVAL_MS: i64 = 5000;
+const EXPORT_TIMEOUT_MS: i64 = 10000;
+const GRPC_EXPORT_PATH: &str = "/opentelemetry.proto.collector.trace.v1.TraceService/Export";
+
+const HEADER_TRACEPARENT: &str = "traceparent";
+const HEADER_TRACESTATE: &str = "tracestate";
+const HEADER_B3: &str = "b3";
+const HEADER_B3_TRACE_ID: &str = "x-b3-traceid";
+const HEADER_B3_SPAN_ID: &str = "x-b3-spanid";
+const HEADER_B3_PARENT_SPAN_ID: &str = "x-b3-parentspanid";
+const HEADER_B3_SAMPLED: &str = "x-b3-sampled";
+const HEADER_B3_FLAGS: &str = "x-b3-flags";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OtlpProtocol {
+    Http,
+    Grpc,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Propagation {
+    W3c,
+    B3,
+    B3Multi,
+}
+
+#[derive(Clone, Debug)]
+pub struct TracingSettings {
+    pub endpoint: String,
+    pub protocol: OtlpProtocol,
+    pub propagation: Vec<Propagation>,
+    pub sample_ratio: f64,
+    pub service_name: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+    pub trace_state: String,
+}
+
+// context extracted from a request, sampled is None when the caller did not decide
+struct RemoteContext {
+    trace_id: [u8; 16],
+    span_id: Option<[u8; 8]>,
+    sampled: Option<bool>,
+    trace_state: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanKind {
+    Server = 2,
+    Client = 3,
+}
+
+#[derive(Clone, Debug)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+}
+
+struct SpanData {
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: DateTime<Local>,
+    end: DateTime<Local>,
+    attributes: Vec<(&'static str, AttributeValue)>,
+    error: Option<String>,
+}
+
+// Span being recorded, exported when ended if sampled
+pub struct Span {
+    tracer: Arc<Tracer>,
+    data: SpanData,
+}
+
+pub struct Tracer {
+    settings: TracingSettings,
+    sender: mpsc::Sender<SpanData>,
+}
+
+// Server span context of a request, stored in the request extensions so the proxy can
+// start the upstream spans under it
+#[derive(Clone)]
+pub struct Trace {
+    tracer: Arc<Tracer>,
+    pub context: SpanContext,
+}
+
+struct Exporter {
+    settings: TracingSettings,
+    receiver: mpsc::Receiver<SpanData>,
+}
+
+impl FromStr for OtlpProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(OtlpProtocol::Http),
+            "grpc" => Ok(OtlpProtocol::Grpc),
+            _ => Err(format!("unknown otlp protocol: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Propagation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "w3c" => Ok(Propagation::W3c),
+            "b3" => Ok(Propagation::B3),
+            "b3multi" => Ok(Propagation::B3Multi),
+            _ => Err(format!("unknown trace propagation: {}", s)),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// decodes exactly N bytes of hex, all zero ids are invalid
+fn parse_id<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut id = [0u8; N];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    if id.iter().all(|b| *b == 0) {
+        None
+    } else {
+        Some(id)
+    }
+}
+
+// b3 trace ids are 64 or 128 bits, short ones are left padded
+fn parse_b3_trace_id(s: &str) -> Option<[u8; 16]> {
+    if s.len() == 16 {
+        parse_id::<16>(&format!("{:0>32}", s))
+    } else {
+        parse_id::<16>(s)
+    }
+}
+
+fn parse_b3_sampled(s: &str) -> Option<bool> {
+    match s {
+        "1" | "d" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim())
+}
+
+fn extract_w3c(headers: &HeaderMap) -> Option<RemoteContext> {
+    let traceparent = header(headers, HEADER_TRACEPARENT)?;
+    let parts: Vec<&str> = traceparent.split('-').collect();
+    // version 00 has exactly 4 fields, later versions may append more
+    if parts.len() < 4 || parts[0].len() != 2 || parts[0] == "ff" {
+        return None;
+    }
+    u8::from_str_radix(parts[0], 16).ok()?;
+    if parts[0] == "00" && parts.len() != 4 {
+        return None;
+    }
+    if parts[3].len() != 2 {
+        return None;
+    }
+    let flags = u8::from_str_radix(parts[3], 16).ok()?;
+    // tracestate may be split over several headers
+    let trace_state = headers
+        .get_all(HEADER_TRACESTATE)
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<&str>>()
+        .join(",");
+    Some(RemoteContext {
+        trace_id: parse_id(parts[1])?,
+        span_id: Some(parse_id(parts[2])?),
+        sampled: Some(flags & 1 == 1),
+        trace_state: if trace_state.len() <= 512 {
+            trace_state
+        } else {
+            String::new()
+        },
+    })
+}
+
+fn extract_b3(headers: &HeaderMap) -> Option<RemoteContext> {
+    // {trace id}-{span id}[-{sampling}[-{parent span id}]], a lone sampling flag has no context
+    let parts: Vec<&str> = header(headers, HEADER_B3)?.split('-').collect();
+    if parts.len() < 2 {
+        return None;
+    }
+    Some(RemoteContext {
+        trace_id: parse_b3_trace_id(parts[0])?,
+        span_id: Some(parse_id(parts[1])?),
+        sampled: parts.get(2).and_then(|s| parse_b3_sampled(s)),
+        trace_state: String::new(),
+    })
+}
+
+fn extract_b3_multi(headers: &HeaderMap) -> Option<RemoteContext> {
+    let sampled = if header(headers, HEADER_B3_FLAGS) == Some("1") {
+        Some(true)
+    } else {
+        header(headers, HEADER_B3_SAMPLED).and_then(parse_b3_sampled)
+    };
+    Some(RemoteContext {
+        trace_id: parse_b3_trace_id(header(headers, HEADER_B3_TRACE_ID)?)?,
+        span_id: header(headers, HEADER_B3_SPAN_ID).and_then(parse_id),
+        sampled,
+        trace_state: String::new(),
+    })
+}
+
+impl SpanContext {
+    pub fn trace_id_hex(&self) -> String {
+        hex(&self.trace_id)
+    }
+
+    fn random_span_id() -> [u8; 8] {
+        loop {
+            let id: [u8; 8] = rand::random();
+            if id != [0; 8] {
+                return id;
+            }
+        }
+    }
+
+    fn random_trace_id() -> [u8; 16] {
+        loop {
+            let id: [u8; 16] = rand::random();
+            if id != [0; 16] {
+                return id;
+            }
+        }
+    }
+
+    // new span in the same trace
+    fn child(&self) -> SpanContext {
+        SpanContext {
+            trace_id: self.trace_id,
+            span_id: SpanContext::random_span_id(),
+            sampled: self.sampled,
+            trace_state: self.trace_state.clone(),
+        }
+    }
+}
+
+impl Span {
+    pub fn set_attribute(&mut self, key: &'static str, value: AttributeValue) {
+        self.data.attributes.push((key, value));
+    }
+
+    pub fn set_error(&mut self, message: String) {
+        self.data.error = Some(message);
+    }
+
+    pub fn context(&self) -> &SpanContext {
+        &self.data.context
+    }
+
+    pub fn end(mut self) {
+        if !self.data.context.sampled {
+            return;
+        }
+        self.data.end = Local::now();
+        // never block a request on the exporter
+        let _ = self.tracer.sender.try_send(self.data);
+    }
+}
+
+impl Trace {
+    // span for an upstream request under the server span
+    pub fn client_span(&self, name: String) -> Span {
+        self.tracer.span(
+            self.context.child(),
+            Some(self.context.span_id),
+            name,
+            SpanKind::Client,
+        )
+    }
+
+    // trace context headers for an upstream request made by span, replacing the incoming ones
+    pub fn inject(&self, span: &Span, headers: &mut HeaderMap) {
+        for name in &[
+            HEADER_TRACEPARENT,
+            HEADER_TRACESTATE,
+            HEADER_B3,
+            HEADER_B3_TRACE_ID,
+            HEADER_B3_SPAN_ID,
+            HEADER_B3_PARENT_SPAN_ID,
+            HEADER_B3_SAMPLED,
+            HEADER_B3_FLAGS,
+        ] {
+            headers.remove(*name);
+        }
+        let context = span.context();
+        let trace_id = hex(&context.trace_id);
+        let span_id = hex(&context.span_id);
+        let parent_span_id = hex(&self.context.span_id);
+        let sampled = if context.sampled { "1" } else { "0" };
+        let mut set = |name: &'static str, value: String| {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        };
+        for propagation in self.tracer.settings.propagation.iter() {
+            match propagation {
+                Propagation::W3c => {
+                    set(
+                        HEADER_TRACEPARENT,
+                        format!("00-{}-{}-0{}", trace_id, span_id, sampled),
+                    );
+                    if !context.trace_state.is_empty() {
+                        set(HEADER_TRACESTATE, context.trace_state.clone());
+                    }
+                }
+                Propagation::B3 => set(
+                    HEADER_B3,
+                    format!("{}-{}-{}-{}", trace_id, span_id, sampled, parent_span_id),
+                ),
+                Propagation::B3Multi => {
+                    set(HEADER_B3_TRACE_ID, trace_id.clone());
+                    set(HEADER_B3_SPAN_ID, span_id.clone());
+                    set(HEADER_B3_PARENT_SPAN_ID, parent_span_id.clone());
+                    set(HEADER_B3_SAMPLED, sampled.to_string());
+                }
+            }
+        }
+    }
+}
+
+impl Tracer {
+    // starts the exporter, must be called from the actix system
+    pub fn start(settings: TracingSettings) -> Arc<Self> {
+        info!(
+            "Tracing enabled: {:?} export to {}, {:?} propagation",
+            settings.protocol, settings.endpoint, settings.propagation
+        );
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let exporter = Exporter {
+            settings: settings.clone(),
+            receiver,
+        };
+        actix_web::rt::spawn(exporter.run());
+        Arc::new(Self { settings, sender })
+    }
+
+    fn span(
+        self: &Arc<Self>,
+        context: SpanContext,
+        parent_span_id: Option<[u8; 8]>,
+        name: String,
+        kind: SpanKind,
+    ) -> Span {
+        let now = Local::now();
+        Span {
+            tracer: self.clone(),
+            data: SpanData {
+                context,
+                parent_span_id,
+                name,
+                kind,
+                start: now,
+                end: now,
+                attributes: Vec::new(),
+                error: None,
+            },
+        }
+    }
+
+    // server span for a request, continuing the first trace context found in its headers
+    fn server_span(self: &Arc<Self>, headers: &HeaderMap, name: String) -> Span {
+        let remote = self.settings.propagation.iter().find_map(|p| match p {
+            Propagation::W3c => extract_w3c(headers),
+            Propagation::B3 => extract_b3(headers),
+            Propagation::B3Multi => extract_b3_multi(headers),
+        });
+        let (trace_id, parent_span_id, sampled, trace_state) = match remote {
+            Some(r) => (r.trace_id, r.span_id, r.sampled, r.trace_state),
+            None => (SpanContext::random_trace_id(), None, None, String::new()),
+        };
+        let sampled = sampled.unwrap_or_else(|| rand::random::<f64>() < self.settings.sample_ratio);
+        let context = SpanContext {
+            trace_id,
+            span_id: SpanContext::random_span_id(),
+            sampled,
+            trace_state,
+        };
+        self.span(context, parent_span_id, name, SpanKind::Server)
+    }
+}
+
+// OTLP/JSON, ids are hex encoded and timestamps are nanoseconds as strings
+fn json_attribute(key: &str, value: &AttributeValue) -> serde_json::Value {
+    match value {
+        AttributeValue::String(s) => json!({"key": key, "value": {"stringValue": s}}),
+        AttributeValue::Int(i) => json!({"key": key, "value": {"intValue": i.to_string()}}),
+    }
+}
+
+fn json_request(service_name: &str, spans: &[SpanData]) -> serde_json::Value {
+    let spans: Vec<serde_json::Value> = spans
+        .iter()
+        .map(|s| {
+            let mut span = json!({
+                "traceId": hex(&s.context.trace_id),
+                "spanId": hex(&s.context.span_id),
+                "name": s.name,
+                "kind": s.kind as u8,
+                "startTimeUnixNano": s.start.timestamp_nanos().to_string(),
+                "endTimeUnixNano": s.end.timestamp_nanos().to_string(),
+                "attributes": s.attributes.iter().map(|(k, v)| json_attribute(k, v)).collect::<Vec<_>>(),
+                "status": match &s.error {
+                    Some(message) => json!({"code": 2, "message": message}),
+                    None => json!({"code": 0}),
+                },
+            });
+            if !s.context.trace_state.is_empty() {
+                span["traceState"] = json!(s.context.trace_state);
+            }
+            if let Some(parent) = s.parent_span_id {
+                span["parentSpanId"] = json!(hex(&parent));
+            }
+            span
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [json_attribute("service.name", &AttributeValue::String(service_name.to_string()))],
+            },
+            "scopeSpans": [{
+                "scope": {"name": "gasket", "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+// Minimal protobuf writer for the OTLP export request
+struct ProtoWriter(Vec<u8>);
+
+impl ProtoWriter {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint(field << 3 | wire_type);
+    }
+
+    fn uint(&mut self, field: u64, v: u64) {
+        if v != 0 {
+            self.key(field, 0);
+            self.varint(v);
+        }
+    }
+
+    fn fixed64(&mut self, field: u64, v: u64) {
+        self.key(field, 1);
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u64, v: &[u8]) {
+        if !v.is_empty() {
+            self.key(field, 2);
+            self.varint(v.len() as u64);
+            self.0.extend_from_slice(v);
+        }
+    }
+
+    fn string(&mut self, field: u64, v: &str) {
+        self.bytes(field, v.as_bytes());
+    }
+
+    fn message(&mut self, field: u64, f: impl FnOnce(&mut ProtoWriter)) {
+        let mut inner = ProtoWriter(Vec::new());
+        f(&mut inner);
+        self.key(field, 2);
+        self.varint(inner.0.len() as u64);
+        self.0.extend_from_slice(&inner.0);
+    }
+
+    // KeyValue { key = 1, AnyValue value = 2 { string_value = 1, int_value = 3 } }
+    fn attribute(&mut self, field: u64, key: &str, value: &AttributeValue) {
+        self.message(field, |kv| {
+            kv.string(1, key);
+            kv.message(2, |v| match value {
+                AttributeValue::String(s) => {
+                    v.key(1, 2);
+                    v.varint(s.len() as u64);
+                    v.0.extend_from_slice(s.as_bytes());
+                }
+                AttributeValue::Int(i) => {
+                    v.key(3, 0);
+                    v.varint(*i as u64);
+                }
+            });
+        });
+    }
+}
+
+// ExportTraceServiceRequest { ResourceSpans resource_spans = 1 {
+//   Resource resource = 1, ScopeSpans scope_spans = 2 { InstrumentationScope scope = 1, Span spans = 2 } } }
+fn proto_request(service_name: &str, spans: &[SpanData]) -> Vec<u8> {
+    let mut w = ProtoWriter(Vec::new());
+    w.message(1, |rs| {
+        rs.message(1, |r| {
+            r.attribute(
+                1,
+                "service.name",
+                &AttributeValue::String(service_name.to_string()),
+            )
+        });
+        rs.message(2, |ss| {
+            ss.message(1, |scope| {
+                scope.string(1, "gasket");
+                scope.string(2, env!("CARGO_PKG_VERSION"));
+            });
+            for s in spans {
+                ss.message(2, |span| {
+                    span.bytes(1, &s.context.trace_id);
+                    span.bytes(2, &s.context.span_id);
+                    span.string(3, &s.context.trace_state);
+                    if let Some(parent) = s.parent_span_id {
+                        span.bytes(4, &parent);
+                    }
+                    span.string(5, &s.name);
+                    span.uint(6, s.kind as u64);
+                    span.fixed64(7, s.start.timestamp_nanos() as u64);
+                    span.fixed64(8, s.end.timestamp_nanos() as u64);
+                    for (k, v) in s.attributes.iter() {
+                        span.attribute(9, k, v);
+                    }
+                    // Status { message = 2, code = 3 }
+                    span.message(15, |status| {
+                        if let Some(message) = &s.error {
+                            status.string(2, message);
+                            status.uint(3, 2);
+                        }
+                    });
+                });
+            }
+        });
+    });
+    w.0
+}
+
+impl Exporter {
+    async fn run(mut self) {
+        let interval = Duration::milliseconds(EXPORT_INTERVAL_MS);
+        let mut batch = Vec::new();
+        let mut next_export = Local::now() + interval;
+        loop {
+            let wait = (next_export - Local::now()).to_std().unwrap_or_default();
+            match actix_web::rt::time::timeout(wait, self.receiver.recv()).await {
+                Ok(Some(span)) => {
+                    batch.push(span);
+                    if batch.len() < BATCH_SIZE {
+                        continue;
+                    }
+                }
+                Ok(None) => {
+                    self.export(std::mem::take(&mut batch)).await;
+                    return;
+                }
+                Err(_) => {}
+            }
+            self.export(std::mem::take(&mut batch)).await;
+            next_export = Local::now() + interval;
+        }
+    }
+
+    async fn export(&self, spans: Vec<SpanData>) {
+        if spans.is_empty() {
+            return;
+        }
+        let result = match self.settings.protocol {
+            OtlpProtocol::Http => self.export_http(&spans).await,
+            OtlpProtocol::Grpc => self.export_grpc(&spans).await,
+        };
+        if let Err(e) = result {
+            info!("Span export failed, dropping {} spans: {}", spans.len(), e);
+        }
+    }
+
+    async fn export_http(&self, spans: &[SpanData]) -> Result<(), String> {
+        let url = format!("{}/v1/traces", self.settings.endpoint.trim_end_matches('/'));
+        let res = awc::Client::new()
+            .post(url)
+            .timeout(
+                Duration::milliseconds(EXPORT_TIMEOUT_MS)
+                    .to_std()
+                    .unwrap_or_default(),
+            )
+            .send_json(&json_request(&self.settings.service_name, spans))
+            .await
+            .map_err(|e| e.to_string())?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("collector answered {}", res.status()))
+        }
+    }
+
+    async fn export_grpc(&self, spans: &[SpanData]) -> Result<(), String> {
+        let timeout = Duration::milliseconds(EXPORT_TIMEOUT_MS)
+            .to_std()
+            .unwrap_or_default();
+        match actix_web::rt::time::timeout(timeout, self.send_grpc(spans)).await {
+            Ok(result) => result,
+            Err(_) => Err("timeout".to_string()),
+        }
+    }
+
+    async fn send_grpc(&self, spans: &[SpanData]) -> Result<(), String> {
+        let url = url::Url::parse(&self.settings.endpoint).map_err(|e| e.to_string())?;
+        let host = url.host_str().ok_or("endpoint without host")?.to_string();
+        let port = url.port().unwrap_or(4317);
+        let tcp = actix_web::rt::net::TcpStream::connect((host.as_str(), port))
+            .await
+            .map_err(|e| e.to_string())?;
+        let (client, connection) = h2::client::handshake(tcp)
+            .await
+            .map_err(|e| e.to_string())?;
+        actix_web::rt::spawn(async move {
+            let _ = connection.await;
+        });
+        let mut client = client.ready().await.map_err(|e| e.to_string())?;
+
+        // length prefixed message: compression flag, big endian length, protobuf
+        let message = proto_request(&self.settings.service_name, spans);
+        let mut body = Vec::with_capacity(message.len() + 5);
+        body.push(0);
+        body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        body.extend_from_slice(&message);
+
+        let request = http::Request::post(format!("http://{}:{}{}", host, port, GRPC_EXPORT_PATH))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(())
+            .map_err(|e| e.to_string())?;
+        let (response, mut stream) = client
+            .send_request(request, false)
+            .map_err(|e| e.to_string())?;
+        stream
+            .send_data(bytes::Bytes::from(body), true)
+            .map_err(|e| e.to_string())?;
+        let response = response.await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("collector answered {}", response.status()));
+        }
+
+        // grpc-status comes in the trailers, or in the headers of a trailers only response
+        let mut grpc_status = response
+            .headers()
+            .get("grpc-status")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let mut body = response.into_body();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| e.to_string())?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+        }
+        if let Some(trailers) = body.trailers().await.map_err(|e| e.to_string())? {
+            if let Some(status) = trailers.get("grpc-status").and_then(|v| v.to_str().ok()) {
+                grpc_status = Some(status.to_string());
+            }
+        }
+        match grpc_status.as_deref() {
+            Some("0") => Ok(()),
+            Some(status) => Err(format!("grpc status {}", status)),
+            None => Err("missing grpc status".to_string()),
+        }
+    }
+}
+
+// Middleware starting the server span of each request, it ends when the response is ready
+pub struct RequestTracing {
+    tracer: Option<Arc<Tracer>>,
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+    tracer: Option<Arc<Tracer>>,
+}
+
+impl RequestTracing {
+    pub fn new(tracer: Option<Arc<Tracer>>) -> Self {
+        Self { tracer }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestTracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTracingMiddleware {
+            service,
+            tracer: self.tracer.clone(),
+        })
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let tracer = match self.tracer.as_ref() {
+            Some(tracer) => tracer,
+            None => return Box::pin(self.service.call(req)),
+        };
+        let method = req.method().to_string();
+        let mut span = tracer.server_span(req.headers(), method.clone());
+        span.set_attribute("http.method", AttributeValue::String(method.clone()));
+        span.set_attribute("http.target", AttributeValue::String(req.uri().to_string()));
+        if let Some(addr) = req.peer_addr() {
+            span.set_attribute("net.peer.ip", AttributeValue::String(addr.ip().to_string()));
+        }
+        req.extensions_mut().insert(Trace {
+            tracer: tracer.clone(),
+            context: span.context().clone(),
+        });
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(res) => {
+                    // the route key is resolved further down by the metrics middleware
+                    if let Some(route) = res.request().extensions().get::<RouteKey>() {
+                        span.data.name = format!("{} {}", method, route.0);
+                        span.set_attribute("http.route", AttributeValue::String(route.0.clone()));
+                    }
+                    res.status()
+                }
+                Err(e) => e.as_response_error().status_code(),
+            };
+            span.set_attribute(
+                "http.status_code",
+                AttributeValue::Int(status.as_u16() as i64),
+            );
+            if status.is_server_error() {
+                span.set_error(status.to_string());
+            }
+            span.end();
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn traceparent(value: &str) -> Option<RemoteContext> {
+        extract_w3c(&headers(&[(HEADER_TRACEPARENT, value)]))
+    }
+
+    fn settings(propagation: Vec<Propagation>) -> TracingSettings {
+        TracingSettings {
+            endpoint: "http://127.0.0.1:4318".to_string(),
+            protocol: OtlpProtocol::Http,
+            propagation,
+            sample_ratio: 1.0,
+            service_name: "svc".to_string(),
+        }
+    }
+
+    fn span_data() -> SpanData {
+        SpanData {
+            context: SpanContext {
+                trace_id: [1; 16],
+                span_id: [2; 8],
+                sampled: true,
+                trace_state: String::new(),
+            },
+            parent_span_id: None,
+            name: "GET".to_string(),
+            kind: SpanKind::Server,
+            start: Local.timestamp(1, 0),
+            end: Local.timestamp(2, 0),
+            attributes: vec![("n", AttributeValue::Int(300))],
+            error: None,
+        }
+    }
+
+    #[test]
+    fn w3c() {
+        let remote = traceparent(&format!("00-{}-{}-01", TRACE_ID, SPAN_ID)).unwrap();
+        assert_eq!(hex(&remote.trace_id), TRACE_ID);
+        assert_eq!(hex(&remote.span_id.unwrap()), SPAN_ID);
+        assert_eq!(remote.sampled, Some(true));
+        let remote = traceparent(&format!("00-{}-{}-00", TRACE_ID, SPAN_ID)).unwrap();
+        assert_eq!(remote.sampled, Some(false));
+
+        let remote = extract_w3c(&headers(&[
+            (
+                HEADER_TRACEPARENT,
+                &format!("00-{}-{}-01", TRACE_ID, SPAN_ID),
+            ),
+            (HEADER_TRACESTATE, "a=1"),
+            (HEADER_TRACESTATE, "b=2"),
+        ]))
+        .unwrap();
+        assert_eq!(remote.trace_state, "a=1,b=2");
+    }
+
+    #[test]
+    fn w3c_invalid() {
+        let zero_trace = "0".repeat(32);
+        let zero_span = "0".repeat(16);
+        for value in [
+            format!("00-{}-{}-01", zero_trace, SPAN_ID),
+            format!("00-{}-{}-01", TRACE_ID, zero_span),
+            format!("00-{}-{}-01-extra", TRACE_ID, SPAN_ID),
+            format!("ff-{}-{}-01", TRACE_ID, SPAN_ID),
+            format!("0x-{}-{}-01", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}-1", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}-01", &TRACE_ID[1..], SPAN_ID),
+            format!("00-{}-{}-01", TRACE_ID.replace('4', "g"), SPAN_ID),
+            "garbage".to_string(),
+        ] {
+            assert!(traceparent(&value).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn w3c_future_version() {
+        // later versions may append fields, the known ones are still read
+        let remote = traceparent(&format!("cc-{}-{}-01-what-comes", TRACE_ID, SPAN_ID)).unwrap();
+        assert_eq!(hex(&remote.trace_id), TRACE_ID);
+        assert_eq!(remote.sampled, Some(true));
+    }
+
+    #[test]
+    fn b3() {
+        let remote = extract_b3(&headers(&[(
+            HEADER_B3,
+            &format!("{}-{}-1", TRACE_ID, SPAN_ID),
+        )]))
+        .unwrap();
+        assert_eq!(hex(&remote.trace_id), TRACE_ID);
+        assert_eq!(hex(&remote.span_id.unwrap()), SPAN_ID);
+        assert_eq!(remote.sampled, Some(true));
+
+        // 64 bit trace ids are left padded, no sampling decision
+        let remote = extract_b3(&headers(&[(
+            HEADER_B3,
+            &format!("{}-{}", "a3ce929d0e0e4736", SPAN_ID),
+        )]))
+        .unwrap();
+        assert_eq!(hex(&remote.trace_id), "0000000000000000a3ce929d0e0e4736");
+        assert_eq!(remote.sampled, None);
+
+        assert!(extract_b3(&headers(&[(HEADER_B3, "0")])).is_none());
+        assert!(extract_b3(&headers(&[(
+            HEADER_B3,
+            &format!("{}-{}", TRACE_ID, "0".repeat(16))
+        )]))
+        .is_none());
+    }
+
+    #[test]
+    fn b3_multi() {
+        let remote = extract_b3_multi(&headers(&[
+            (HEADER_B3_TRACE_ID, TRACE_ID),
+            (HEADER_B3_SPAN_ID, SPAN_ID),
+            (HEADER_B3_SAMPLED, "0"),
+        ]))
+        .unwrap();
+        assert_eq!(hex(&remote.trace_id), TRACE_ID);
+        assert_eq!(hex(&remote.span_id.unwrap()), SPAN_ID);
+        assert_eq!(remote.sampled, Some(false));
+
+        // debug flag forces sampling
+        let remote = extract_b3_multi(&headers(&[
+            (HEADER_B3_TRACE_ID, TRACE_ID),
+            (HEADER_B3_SAMPLED, "0"),
+            (HEADER_B3_FLAGS, "1"),
+        ]))
+        .unwrap();
+        assert_eq!(remote.sampled, Some(true));
+        assert!(remote.span_id.is_none());
+
+        assert!(extract_b3_multi(&headers(&[(HEADER_B3_SPAN_ID, SPAN_ID)])).is_none());
+    }
+
+    #[test]
+    fn inject_round_trip() {
+        let (sender, _receiver) = mpsc::channel(1);
+        let tracer = Arc::new(Tracer {
+            settings: settings(vec![
+                Propagation::W3c,
+                Propagation::B3,
+                Propagation::B3Multi,
+            ]),
+            sender,
+        });
+        let trace = Trace {
+            tracer,
+            context: SpanContext {
+                trace_id: parse_id(TRACE_ID).unwrap(),
+                span_id: parse_id(SPAN_ID).unwrap(),
+                sampled: true,
+                trace_state: "a=1".to_string(),
+            },
+        };
+        let span = trace.client_span("GET".to_string());
+        // incoming headers are replaced
+        let mut injected = headers(&[(HEADER_B3_FLAGS, "1"), (HEADER_B3, "0")]);
+        trace.inject(&span, &mut injected);
+        assert!(injected.get(HEADER_B3_FLAGS).is_none());
+        assert_eq!(header(&injected, HEADER_B3_PARENT_SPAN_ID), Some(SPAN_ID));
+
+        for remote in [
+            extract_w3c(&injected),
+            extract_b3(&injected),
+            extract_b3_multi(&injected),
+        ] {
+            let remote = remote.unwrap();
+            assert_eq!(hex(&remote.trace_id), TRACE_ID);
+            assert_eq!(remote.span_id, Some(span.context().span_id));
+            assert_eq!(remote.sampled, Some(true));
+        }
+        assert_eq!(extract_w3c(&injected).unwrap().trace_state, "a=1");
+    }
+
+    // length delimited field of a protobuf message
+    fn field(tag: u8, content: &[u8]) -> Vec<u8> {
+        assert!(content.len() < 128);
+        let mut out = vec![tag, content.len() as u8];
+        out.extend_from_slice(content);
+        out
+    }
+
+    #[test]
+    fn proto() {
+        let version = env!("CARGO_PKG_VERSION").as_bytes();
+        let mut span = field(0x0a, &[1; 16]); // trace_id = 1
+        span.extend(field(0x12, &[2; 8])); // span_id = 2
+        span.extend(field(0x2a, b"GET")); // name = 5
+        span.extend([0x30, 2]); // kind = 6
+        span.push(0x39); // start_time_unix_nano = 7, fixed64
+        span.extend(1_000_000_000u64.to_le_bytes());
+        span.push(0x41); // end_time_unix_nano = 8
+        span.extend(2_000_000_000u64.to_le_bytes());
+        // attributes = 9 { key = 1, value = 2 { int_value = 3: 300 } }
+        let mut attribute = field(0x0a, b"n");
+        attribute.extend(field(0x12, &[0x18, 0xac, 0x02]));
+        span.extend(field(0x4a, &attribute));
+        span.extend(field(0x7a, &[])); // status = 15, ok
+        let mut scope = field(0x0a, b"gasket");
+        scope.extend(field(0x12, version));
+        let mut scope_spans = field(0x0a, &scope);
+        scope_spans.extend(field(0x12, &span));
+        let mut service = field(0x0a, b"service.name");
+        service.extend(field(0x12, &field(0x0a, b"svc")));
+        let mut resource_spans = field(0x0a, &field(0x0a, &service));
+        resource_spans.extend(field(0x12, &scope_spans));
+        let expected = field(0x0a, &resource_spans);
+
+        assert_eq!(proto_request("svc", &[span_data()]), expected);
+    }
+
+    // answers one request with status, returns the request line and the body
+    fn stub_collector(status: u16) -> (u16, std::thread::JoinHandle<(String, String)>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let response = format!("HTTP/1.1 {} X\r\ncontent-length: 0\r\n\r\n", status);
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            (
+                request_line.trim().to_string(),
+                String::from_utf8(body).unwrap(),
+            )
+        });
+        (port, handle)
+    }
+
+    fn exporter(port: u16) -> Exporter {
+        let (_sender, receiver) = mpsc::channel(1);
+        Exporter {
+            settings: TracingSettings {
+                endpoint: format!("http://127.0.0.1:{}/", port),
+                ..settings(vec![Propagation::W3c])
+            },
+            receiver,
+        }
+    }
+
+    #[test]
+    fn export_http() {
+        actix_web::rt::System::new().block_on(async {
+            let (port, collector) = stub_collector(200);
+            exporter(port).export_http(&[span_data()]).await.unwrap();
+            let (request_line, body) = collector.join().unwrap();
+            assert_eq!(request_line, "POST /v1/traces HTTP/1.1");
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            let resource_spans = &body["resourceSpans"][0];
+            assert_eq!(
+                resource_spans["resource"]["attributes"][0]["value"]["stringValue"],
+                "svc"
+            );
+            let span = &resource_spans["scopeSpans"][0]["spans"][0];
+            assert_eq!(span["traceId"], hex(&[1; 16]));
+            assert_eq!(span["spanId"], hex(&[2; 8]));
+            assert_eq!(span["startTimeUnixNano"], "1000000000");
+            assert_eq!(span["attributes"][0]["value"]["intValue"], "300");
+
+            let (port, collector) = stub_collector(503);
+            assert!(exporter(port).export_http(&[span_data()]).await.is_err());
+            collector.join().unwrap();
+        });
+    }
+}
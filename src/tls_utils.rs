@@ -1,13 +1,180 @@
+use openssl::asn1::Asn1Time;
+use openssl::nid::Nid;
 use openssl::ssl::{
-    SslAcceptor, SslFiletype, SslMethod, SslSessionCacheMode, SslVerifyMode, SslVersion,
+    SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod,
+    SslSessionCacheMode, SslVerifyMode, SslVersion,
 };
 use openssl::x509::store::X509StoreBuilder;
 use openssl::x509::X509;
 
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::ffi::{c_void, CStr};
 use std::fs;
+use std::os::raw::{c_char, c_int};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+// TLS handshake failures: openssl reports every alert to the info callback, fatal ones abort
+// the connection and are counted by side (server: sent by gasket, client: received) and reason
+extern "C" {
+    fn SSL_CTX_set_info_callback(
+        ctx: *mut c_void,
+        cb: Option<unsafe extern "C" fn(*const c_void, c_int, c_int)>,
+    );
+    fn SSL_alert_desc_string_long(value: c_int) -> *const c_char;
+}
+
+const SSL_CB_WRITE: c_int = 0x08;
+const SSL_CB_ALERT: c_int = 0x4000;
+const SSL3_AL_FATAL: c_int = 2;
+
+static HANDSHAKE_FAILURES: Mutex<BTreeMap<(String, String), u64>> = Mutex::new(BTreeMap::new());
+
+unsafe extern "C" fn tls_info_callback(_ssl: *const c_void, event: c_int, value: c_int) {
+    if event & SSL_CB_ALERT == 0 || value >> 8 != SSL3_AL_FATAL {
+        return;
+    }
+    let side = if event & SSL_CB_WRITE != 0 {
+        "server"
+    } else {
+        "client"
+    };
+    let reason = CStr::from_ptr(SSL_alert_desc_string_long(value))
+        .to_string_lossy()
+        .into_owned();
+    *HANDSHAKE_FAILURES
+        .lock()
+        .unwrap()
+        .entry((side.to_string(), reason))
+        .or_insert(0) += 1;
+}
+
+// fatal alerts seen so far, by side and reason
+pub fn handshake_failures() -> Vec<((String, String), u64)> {
+    HANDSHAKE_FAILURES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), *v))
+        .collect()
+}
+
+// TLS reload: the acceptor of each server is built again from the same files, new handshakes
+// switch to the newest context in the servername callback (called on every ClientHello, with
+// or without SNI), established connections keep theirs
+type BuildAcceptor = Box<dyn Fn() -> Result<SslAcceptorBuilder, std::io::Error> + Send>;
+
+struct Reloadable {
+    build: BuildAcceptor,
+    context: Arc<RwLock<SslContext>>,
+}
+
+static RELOADABLE: Mutex<Vec<Reloadable>> = Mutex::new(Vec::new());
+// bumped on every reload, the certificate statuses are read again
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// an acceptor builder that follows reloads
+pub fn reloadable(build: BuildAcceptor) -> Result<SslAcceptorBuilder, std::io::Error> {
+    let mut builder = build()?;
+    let context = Arc::new(RwLock::new(build()?.build().into_context()));
+    let current = context.clone();
+    builder.set_servername_callback(move |ssl, _alert| {
+        let context = current.read().unwrap();
+        // the certificate, key and client CA store follow the context, the verify mode was
+        // copied from the initial context when the connection was created
+        ssl.set_ssl_context(&context)
+            .map_err(|_| SniError::ALERT_FATAL)?;
+        ssl.set_verify(context.verify_mode());
+        Ok(())
+    });
+    RELOADABLE
+        .lock()
+        .unwrap()
+        .push(Reloadable { build, context });
+    Ok(builder)
+}
+
+// reads the keys and certificates again, nothing changes unless every server could load them
+pub fn reload() -> Result<(), String> {
+    let reloadable = RELOADABLE.lock().unwrap();
+    if reloadable.is_empty() {
+        return Err("no TLS server".to_string());
+    }
+    let contexts = reloadable
+        .iter()
+        .map(|r| (r.build)().map(|builder| builder.build().into_context()))
+        .collect::<Result<Vec<SslContext>, std::io::Error>>()
+        .map_err(|e| e.to_string())?;
+    for (r, context) in reloadable.iter().zip(contexts) {
+        *r.context.write().unwrap() = context;
+    }
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+// mTLS client identity: common name of the verified peer certificate
+#[derive(Clone, Debug)]
+pub struct PeerIdentity(pub String);
+
+// Validity of a certificate loaded at startup, reported by the health endpoints
+#[derive(Clone, Debug, Serialize)]
+pub struct CertificateStatus {
+    pub path: String,
+    pub subject: String,
+    pub not_before: DateTime<Local>,
+    pub not_after: DateTime<Local>,
+}
+
 pub struct CertificateManager {}
 
+impl CertificateStatus {
+    pub fn valid(&self) -> bool {
+        let now = Local::now();
+        self.not_before <= now && now < self.not_after
+    }
+}
+
 impl CertificateManager {
+    // validity of the first certificate of a pem file (the leaf on a chain)
+    pub fn certificate_status(path: String) -> Result<CertificateStatus, std::io::Error> {
+        let pem = fs::read(&path)?;
+        let cert = X509::from_pem(&pem)?;
+        let now = Asn1Time::days_from_now(0)?;
+        let local_now = Local::now();
+        // asn1 times are converted through their distance to now
+        let to_local = |t: &openssl::asn1::Asn1TimeRef| -> Result<DateTime<Local>, std::io::Error> {
+            let diff = now.diff(t)?;
+            Ok(local_now
+                + chrono::Duration::days(diff.days as i64)
+                + chrono::Duration::seconds(diff.secs as i64))
+        };
+        let subject = cert
+            .subject_name()
+            .entries()
+            .filter_map(|e| {
+                let value = e.data().as_utf8().ok()?;
+                Some(format!("{}={}", e.object().nid().short_name().ok()?, value))
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        Ok(CertificateStatus {
+            path,
+            subject,
+            not_before: to_local(cert.not_before())?,
+            not_after: to_local(cert.not_after())?,
+        })
+    }
+
     // private key and pem file (cert chain)
     pub fn new_tls_builder(
         private_key_path: String,
@@ -21,14 +188,12 @@ impl CertificateManager {
 
         builder.set_session_cache_mode(SslSessionCacheMode::OFF);
 
-        builder
-            .set_private_key_file(private_key_path, SslFiletype::PEM)
-            .unwrap();
-        match builder.set_certificate_chain_file(certificate_chain_path) {
-            Ok(_b) => return Ok(builder),
-            Err(e) => return Err(e.into()), //Err(format!("{}", e)),
-        };
-        //Ok(builder)
+        builder.set_private_key_file(private_key_path, SslFiletype::PEM)?;
+        builder.set_certificate_chain_file(certificate_chain_path)?;
+        unsafe {
+            SSL_CTX_set_info_callback(builder.as_ptr() as *mut c_void, Some(tls_info_callback));
+        }
+        Ok(builder)
     }
 
     // "ca/server/client-ssl.key"
@@ -47,10 +212,7 @@ impl CertificateManager {
 
         // build client certificate store
         let mut builder =
-            match CertificateManager::new_tls_builder(private_key_path, certificate_chain_path) {
-                Ok(b) => b,
-                Err(e) => return Err(e.into()),
-            };
+            CertificateManager::new_tls_builder(private_key_path, certificate_chain_path)?;
 
         let ca_cert = fs::read_to_string(client_ca_path)?.into_bytes();
         let client_ca_cert = X509::from_pem(&ca_cert)?;
@@ -68,3 +230,145 @@ impl CertificateManager {
         Ok(builder)
     }
 }
+
+// on_connect callback: stores the peer certificate identity in the connection extensions
+// so it reaches every request handled over that connection
+pub fn peer_identity_on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let tls_stream = match connection.downcast_ref::<TlsStream<TcpStream>>() {
+        Some(s) => s,
+        None => return,
+    };
+    let cert = match tls_stream.ssl().peer_certificate() {
+        Some(c) => c,
+        None => return,
+    };
+    if let Some(cn) = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next() {
+        if let Ok(name) = cn.data().as_utf8() {
+            data.insert(PeerIdentity(name.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::ssl::SslConnector;
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+
+    // a certificate signed by issuer, a CA when self signed
+    fn certificate(cn: &str, issuer: Option<(&X509, &PKey<Private>)>) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+        let name = name.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(rand::random::<u32>() >> 1)
+            .unwrap()
+            .to_asn1_integer()
+            .unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some((issuer, issuer_key)) => {
+                builder.set_issuer_name(issuer.subject_name()).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&name).unwrap();
+                let ca = BasicConstraints::new().critical().ca().build().unwrap();
+                builder.append_extension(ca).unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (builder.build(), key)
+    }
+
+    // whether the server accepts a client certificate
+    fn handshake(acceptor: &SslAcceptor, client: &(X509, PKey<Private>)) -> bool {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_certificate(&client.0).unwrap();
+        connector.set_private_key(&client.1).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let connector = connector.build();
+        let client = std::thread::spawn(move || {
+            let _ = connector
+                .configure()
+                .unwrap()
+                .verify_hostname(false)
+                .connect("localhost", client_stream);
+        });
+        let accepted = acceptor.accept(server_stream).is_ok();
+        client.join().unwrap();
+        accepted
+    }
+
+    fn write_pem(path: &Path, pem: Vec<u8>) {
+        fs::write(path, pem).unwrap();
+    }
+
+    #[test]
+    fn reload_client_ca() {
+        let dir = std::env::temp_dir().join(format!("gasket-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (server, server_key) = certificate("server", None);
+        let old_ca = certificate("old ca", None);
+        let new_ca = certificate("new ca", None);
+        let old_client = certificate("old client", Some((&old_ca.0, &old_ca.1)));
+        let new_client = certificate("new client", Some((&new_ca.0, &new_ca.1)));
+        write_pem(
+            &dir.join("server.key"),
+            server_key.private_key_to_pem_pkcs8().unwrap(),
+        );
+        write_pem(&dir.join("server.crt"), server.to_pem().unwrap());
+        write_pem(&dir.join("ca.crt"), old_ca.0.to_pem().unwrap());
+
+        let files = dir.clone();
+        let acceptor = reloadable(Box::new(move || {
+            let path = |name: &str| files.join(name).to_string_lossy().into_owned();
+            CertificateManager::new_mtls_builder(
+                path("server.key"),
+                path("server.crt"),
+                path("ca.crt"),
+            )
+        }))
+        .unwrap()
+        .build();
+        assert!(handshake(&acceptor, &old_client));
+        assert!(!handshake(&acceptor, &new_client));
+
+        // a CA that can't be read changes nothing
+        let before = generation();
+        fs::write(dir.join("ca.crt"), "not a certificate").unwrap();
+        assert!(reload().is_err());
+        assert_eq!(generation(), before);
+        assert!(handshake(&acceptor, &old_client));
+
+        write_pem(&dir.join("ca.crt"), new_ca.0.to_pem().unwrap());
+        reload().unwrap();
+        assert_eq!(generation(), before + 1);
+        let rotated = (
+            handshake(&acceptor, &new_client),
+            handshake(&acceptor, &old_client),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(rotated, (true, false));
+    }
+}